@@ -1,14 +1,19 @@
-use anyhow::*;
+use anyhow::{bail, Context, Result};
 use clap::ValueEnum;
-use newick::{Newick, NewickTree};
+use newick::{Data, Newick, NewickTree};
+use regex::Regex;
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
-    io::{BufRead, BufReader},
+    io::BufRead,
 };
 use syntesuite::genebook::GeneBook;
 
-use crate::utils::{capitalize, effective_losses, jaccard};
+use crate::errors::{node_name, AnnotationError};
+use crate::species::SpeciesTree;
+use crate::utils::{
+    capitalize, duplication_consistency, effective_losses, extract_subtree, jaccard,
+    losses_on_path, postorder, reroot_above, reroot_within,
+};
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum Strippable {
@@ -17,124 +22,733 @@ pub enum Strippable {
     Length,
 }
 
-pub fn annotate_duplications(t: &mut NewickTree, species_tree: &NewickTree, filter_species: bool) {
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DuplicationMethod {
+    /// a node is a duplication if the species MRCA of a child is an ancestor of another one's
+    Lca,
+    /// a node is a duplication if its children share species, as in Ensembl Compara
+    Overlap,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum OrthogroupFormat {
+    /// one Newick subtree per orthogroup
+    Newick,
+    /// one (orthogroup, gene) pair per line
+    Tsv,
+}
+
+/// Map the leaves of `t`, except losses, to their species in `species_tree`
+fn leaf_species(
+    t: &NewickTree,
+    species_tree: &SpeciesTree,
+) -> Result<HashMap<usize, usize>, AnnotationError> {
+    t.leaves()
+        .filter(|&l| !is_loss(t, l))
+        .map(|l| {
+            let species = t
+                .attrs(l)
+                .get("S")
+                .ok_or_else(|| AnnotationError::MissingSpecies {
+                    node: node_name(t, l),
+                })?;
+            species_tree.leaf(species).map(|s| (l, s)).ok_or_else(|| {
+                AnnotationError::UnknownSpecies {
+                    node: node_name(t, l),
+                    species: species.to_owned(),
+                }
+            })
+        })
+        .collect()
+}
+
+/// Find the node of `species_tree` matching the species `S` of the node `n`
+fn species_of(
+    t: &NewickTree,
+    n: usize,
+    species_tree: &SpeciesTree,
+) -> Result<usize, AnnotationError> {
+    let species = t
+        .attrs(n)
+        .get("S")
+        .ok_or_else(|| AnnotationError::MissingSpecies {
+            node: node_name(t, n),
+        })?;
+    species_tree
+        .node(species)
+        .ok_or_else(|| AnnotationError::UnknownSpecies {
+            node: node_name(t, n),
+            species: species.to_owned(),
+        })
+}
+
+fn species_name(species_tree: &SpeciesTree, s: usize) -> Result<String, AnnotationError> {
+    species_tree
+        .name(s)
+        .cloned()
+        .ok_or(AnnotationError::UnnamedSpecies)
+}
+
+/// Annotate the inner nodes of `t` as duplications (`D=Y`) or speciations
+/// (`D=N`), following `method`. With [`DuplicationMethod::Overlap`], a node is
+/// a duplication only if the species Jaccard index of two of its children is
+/// non-null and at least `min_overlap`. If `soft_polytomies` is set, nodes
+/// with more than two children are considered as unresolved, and are only
+/// annotated as duplications if some of their children share species.
+///
+/// Duplications are further annotated with their duplication consistency
+/// score (`DCS`) and their count of effective losses (`ELC`), of which those
+/// spanning more than one species (`ELLC`).
+pub fn annotate_duplications(
+    t: &mut NewickTree,
+    species_tree: &SpeciesTree,
+    filter_species: bool,
+    method: DuplicationMethod,
+    min_overlap: f32,
+    soft_polytomies: bool,
+) -> Result<(), AnnotationError> {
+    let leaf_species = leaf_species(t, species_tree)?;
     let restricted_species = if filter_species {
-        Some(
-            t.leaves()
-                .filter_map(|l| t.attrs(l).get("S").map(|s| s.to_owned()))
-                .map(|name| {
-                    species_tree
-                        .find_leaf(|n| n.name.as_ref().unwrap().as_str() == name.as_str())
-                        .unwrap_or_else(|| panic!("{} not found in species tree", name))
-                })
-                .collect::<HashSet<_>>(),
-        )
+        Some(leaf_species.values().copied().collect::<HashSet<_>>())
     } else {
         None
     };
-    let all_species: HashSet<_> = species_tree.leaves().collect();
-    t.inners().collect::<Vec<_>>().iter().for_each(|n| {
-        let species: Vec<HashSet<usize>> = t[*n]
+    let all_species: HashSet<_> = species_tree.tree().leaves().collect();
+
+    // The species sets are carried upwards, and dropped as soon as they have
+    // been merged into their parent's
+    let mut species_sets: HashMap<usize, HashSet<usize>> = HashMap::new();
+    for n in postorder(t).into_iter() {
+        if t[n].is_leaf() {
+            species_sets.insert(n, leaf_species.get(&n).into_iter().copied().collect());
+            continue;
+        }
+
+        let species: Vec<HashSet<usize>> = t[n]
             .children()
             .iter()
-            .map(|&c| {
-                t.leaves_of(c)
-                    .iter()
-                    .map(|&n| t.attrs(n).get("S").map(|s| s.to_owned()).unwrap())
-                    .map(|s| {
-                        species_tree
-                            .find_leaf(|l| l.name.as_ref().unwrap().as_str() == s.as_str())
-                            .expect(&format!("{} not found in species tree", s))
-                    })
-                    .collect()
-            })
+            .map(|c| species_sets.remove(c).unwrap_or_default())
             .collect();
         if species.len() >= 2 {
-            let mrcas = species
-                .iter()
-                .cloned()
-                .map(|ss| species_tree.mrca(ss).unwrap())
-                .collect::<Vec<usize>>();
             let mut d = false;
-            'find_d: for (i, &m1) in mrcas.iter().enumerate() {
-                for &m2 in mrcas.iter().skip(i + 1) {
-                    if species_tree.ascendance(m1).contains(&m2)
-                        || species_tree.ascendance(m2).contains(&m1)
-                    {
-                        d = true;
-                        break 'find_d;
+            match method {
+                _ if soft_polytomies && species.len() > 2 => {
+                    'find_shared: for (i, s1) in species.iter().enumerate() {
+                        for s2 in species.iter().skip(i + 1) {
+                            if !s1.is_disjoint(s2) {
+                                d = true;
+                                break 'find_shared;
+                            }
+                        }
+                    }
+                }
+                DuplicationMethod::Lca => {
+                    let mrcas = species
+                        .iter()
+                        .filter_map(|ss| species_tree.mrca_of(ss.iter().copied()))
+                        .collect::<Vec<usize>>();
+                    'find_d: for (i, &m1) in mrcas.iter().enumerate() {
+                        for &m2 in mrcas.iter().skip(i + 1) {
+                            if species_tree.is_ancestor(m1, m2) || species_tree.is_ancestor(m2, m1)
+                            {
+                                d = true;
+                                break 'find_d;
+                            }
+                        }
+                    }
+                }
+                DuplicationMethod::Overlap => {
+                    'find_overlap: for (i, s1) in species.iter().enumerate() {
+                        for s2 in species.iter().skip(i + 1) {
+                            let overlap = jaccard(s1, s2);
+                            if overlap > 0. && overlap >= min_overlap {
+                                d = true;
+                                break 'find_overlap;
+                            }
+                        }
                     }
                 }
             }
 
             if d {
-                let dcs = jaccard(&species[0], &species[1]);
-                let (elc_all, elc_large) = if restricted_species.is_some() {
-                    effective_losses(
-                        &species[0],
-                        &species[1],
-                        species_tree,
-                        restricted_species.as_ref().unwrap(),
-                    )
-                } else {
-                    effective_losses(&species[0], &species[1], species_tree, &all_species)
-                };
-                t.attrs_mut(*n).insert("D".to_string(), "Y".to_owned());
-                t.attrs_mut(*n).insert("DCS".to_string(), dcs.to_string());
-                t.attrs_mut(*n)
+                let dcs = duplication_consistency(&species);
+                let (elc_all, elc_large) = effective_losses(
+                    &species,
+                    species_tree.tree(),
+                    restricted_species.as_ref().unwrap_or(&all_species),
+                );
+                t.attrs_mut(n).insert("D".to_string(), "Y".to_owned());
+                t.attrs_mut(n).insert("DCS".to_string(), dcs.to_string());
+                t.attrs_mut(n)
                     .insert("ELC".to_string(), elc_all.to_string());
-                t.attrs_mut(*n)
+                t.attrs_mut(n)
                     .insert("ELLC".to_string(), elc_large.to_string());
             } else {
-                t.attrs_mut(*n).insert("D".to_string(), "N".to_owned());
+                t.attrs_mut(n).insert("D".to_string(), "N".to_owned());
             }
         }
-    });
+        species_sets.insert(n, species.into_iter().flatten().collect());
+    }
+    Ok(())
 }
 
-pub fn annotate_mrcas(t: &mut NewickTree, species_tree: &NewickTree) -> Result<()> {
-    for n in t.inners().collect::<Vec<_>>().into_iter() {
-        let species: HashSet<usize> = t
-            .leaves_of(n)
+pub fn annotate_mrcas(
+    t: &mut NewickTree,
+    species_tree: &SpeciesTree,
+) -> Result<(), AnnotationError> {
+    let leaf_species = leaf_species(t, species_tree)?;
+    let mut mrcas: HashMap<usize, usize> = HashMap::new();
+    for n in postorder(t).into_iter() {
+        let mrca = if t[n].is_leaf() {
+            leaf_species.get(&n).copied()
+        } else {
+            species_tree.mrca_of(t[n].children().iter().filter_map(|c| mrcas.get(c).copied()))
+        };
+        if let Some(mrca) = mrca {
+            mrcas.insert(n, mrca);
+        }
+    }
+
+    let names = t
+        .inners()
+        .filter_map(|n| mrcas.get(&n).map(|&mrca| (n, mrca)))
+        .map(|(n, mrca)| species_name(species_tree, mrca).map(|name| (n, name)))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (n, name) in names.into_iter() {
+        t.attrs_mut(n).insert("S".to_owned(), name);
+    }
+    Ok(())
+}
+
+/// Complete a tree annotated by [`annotate_mrcas`] and [`annotate_duplications`]
+/// into a reconciled tree, by inserting the speciation nodes implied by the
+/// species tree and explicit loss leaves, named after the lost lineage in the
+/// Notung fashion (`Species*LOST`) and marked with `L=Y` and their species `S`.
+///
+/// If `filter_species` is set, only the species present in the gene tree are
+/// considered to infer the losses.
+pub fn reconcile(
+    t: &mut NewickTree,
+    species_tree: &SpeciesTree,
+    filter_species: bool,
+) -> Result<(), AnnotationError> {
+    let actual_species = if filter_species {
+        leaf_species(t, species_tree)?.into_values().collect()
+    } else {
+        species_tree.tree().leaves().collect()
+    };
+
+    for g in t.inners().collect::<Vec<_>>().into_iter() {
+        let s_g = species_of(t, g, species_tree)?;
+        // The uppermost node of the lineage of each child, in the original order
+        let mut tops = Vec::new();
+        for c in t[g].children().to_vec().into_iter() {
+            let s_c = species_of(t, c, species_tree)?;
+            let mut path = species_tree.tree().ascendance(s_c);
+            if let Some(i) = path.iter().position(|&s| s == s_g) {
+                path.truncate(i);
+            } else {
+                return Err(AnnotationError::InconsistentSpecies {
+                    node: node_name(t, c),
+                    species: species_name(species_tree, s_c)?,
+                    ancestor: species_name(species_tree, s_g)?,
+                });
+            }
+            // The lineage of a speciation child starts below it
+            let top = if t.is_duplication(g) {
+                s_g
+            } else if let Some(&top) = path.last() {
+                top
+            } else {
+                tops.push(c);
+                continue;
+            };
+
+            let mut current = c;
+            for (speciation, lost) in losses_on_path(s_c, top, species_tree.tree(), &actual_species)
+            {
+                let mut attrs = HashMap::new();
+                attrs.insert("S".to_owned(), species_name(species_tree, speciation)?);
+                attrs.insert("D".to_owned(), "N".to_owned());
+                let new = t.add_node(Some(g), newick::Data { name: None, attrs });
+                t.move_node(current, new);
+
+                for l in lost {
+                    let name = species_name(species_tree, l)?;
+                    let mut attrs = HashMap::new();
+                    attrs.insert("S".to_owned(), name.clone());
+                    attrs.insert("L".to_owned(), "Y".to_owned());
+                    t.add_node(
+                        Some(new),
+                        newick::Data {
+                            name: Some(format!("{}*LOST", name)),
+                            attrs,
+                        },
+                    );
+                }
+                current = new;
+            }
+            tops.push(current);
+        }
+        // The speciation nodes were appended to the children of `g`
+        for top in tops {
+            t.move_node(top, g);
+        }
+    }
+
+    Ok(())
+}
+
+/// One side of a branch of a gene tree, summarized by the species of its genes
+#[derive(Clone)]
+struct Clade {
+    /// the species, as bits indexed by their rank among the species tree leaves
+    species: Vec<u64>,
+    /// the MRCA of the species, if any
+    mrca: Option<usize>,
+}
+impl Clade {
+    fn empty(size: usize) -> Self {
+        Clade {
+            species: vec![0; size.div_ceil(64)],
+            mrca: None,
+        }
+    }
+
+    fn merge(&mut self, other: &Clade, species_tree: &SpeciesTree) {
+        for (a, b) in self.species.iter_mut().zip(other.species.iter()) {
+            *a |= b;
+        }
+        self.mrca = match (self.mrca, other.mrca) {
+            (Some(a), Some(b)) => Some(species_tree.mrca(a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+/// The duplications and effective losses implied by a gene tree node whose
+/// children are the `clades`, as annotated by [`annotate_duplications`] with
+/// the LCA method
+fn node_cost(
+    clades: &[&Clade],
+    species_tree: &SpeciesTree,
+    species: &[usize],
+    actual_species: &HashSet<usize>,
+) -> (usize, usize) {
+    if clades.len() < 2 {
+        return (0, 0);
+    }
+    let mrcas = clades.iter().filter_map(|c| c.mrca).collect::<Vec<_>>();
+    let duplication = mrcas.iter().enumerate().any(|(i, &m1)| {
+        mrcas
             .iter()
-            .flat_map(|&c| {
-                t.leaves_of(c)
-                    .into_iter()
-                    .map(|n| t.attrs(n).get("S").map(|s| s.to_owned()).unwrap())
-                    .map(|s| {
-                        species_tree
-                            .find_leaf(|l| l.name.as_ref().unwrap().as_str() == s.as_str())
-                            .ok_or_else(|| anyhow!(format!("{} not found in species tree", s)))
-                    })
-            })
-            .collect::<Result<HashSet<usize>>>()?;
-        let mrca = species_tree.mrca(species.iter().cloned()).unwrap();
-        t.attrs_mut(n)
-            .insert("S".to_owned(), species_tree.name(mrca).unwrap().to_owned());
+            .skip(i + 1)
+            .any(|&m2| species_tree.is_ancestor(m1, m2) || species_tree.is_ancestor(m2, m1))
+    });
+    if !duplication {
+        return (0, 0);
     }
+
+    let sets = clades
+        .iter()
+        .map(|c| {
+            species
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| c.species[i / 64] & (1 << (i % 64)) != 0)
+                .map(|(_, &s)| s)
+                .collect::<HashSet<_>>()
+        })
+        .collect::<Vec<_>>();
+    let (losses, _) = effective_losses(&sets, species_tree.tree(), actual_species);
+    (1, losses)
+}
+
+fn add_costs(a: (usize, usize), b: (usize, usize)) -> (usize, usize) {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+/// Reroot `t` so as to minimize its duplication/loss cost against
+/// `species_tree`, i.e. the number of duplications plus the number of
+/// effective losses, and store this cost in the `DLC` attribute of the root.
+///
+/// Every branch is tried as a root. Ties are broken first by favoring the
+/// rootings with fewer duplications, then by keeping the original rooting if
+/// it is among the best, and finally by choosing the branch above the first
+/// node in a pre-order traversal of the original tree.
+///
+/// The costs of all the rootings are computed at once, from the species of
+/// both sides of every branch. If `t` is rerooted and was annotated, its
+/// annotations are recomputed as [`annotate_mrcas`] and
+/// [`annotate_duplications`] with the LCA method would do.
+pub fn reroot_reconcile(
+    t: &mut NewickTree,
+    species_tree: &SpeciesTree,
+) -> Result<usize, AnnotationError> {
+    if t.is_empty() {
+        return Ok(0);
+    }
+    let leaf_species = leaf_species(t, species_tree)?;
+    let actual_species = leaf_species.values().copied().collect::<HashSet<_>>();
+    let species = species_tree.tree().leaves().collect::<Vec<_>>();
+    let ranks = species
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| (s, i))
+        .collect::<HashMap<_, _>>();
+    let cost = |clades: &[&Clade]| node_cost(clades, species_tree, &species, &actual_species);
+
+    // The clades below each node, and the cost of their subtree
+    let order = postorder(t);
+    let mut down: HashMap<usize, Clade> = HashMap::with_capacity(t.len());
+    let mut down_cost: HashMap<usize, (usize, usize)> = HashMap::with_capacity(t.len());
+    for &n in order.iter() {
+        let mut clade = Clade::empty(species.len());
+        let mut total = (0, 0);
+        if let Some(s) = leaf_species.get(&n) {
+            let i = ranks[s];
+            clade.species[i / 64] |= 1 << (i % 64);
+            clade.mrca = Some(*s);
+        }
+        let children = t[n].children();
+        for c in children {
+            clade.merge(&down[c], species_tree);
+            total = add_costs(total, down_cost[c]);
+        }
+        total = add_costs(
+            total,
+            cost(&children.iter().map(|c| &down[c]).collect::<Vec<_>>()),
+        );
+        down.insert(n, clade);
+        down_cost.insert(n, total);
+    }
+
+    // The clades above each node, and the cost of the rest of the tree when
+    // rooted above it
+    let root = t.root();
+    let mut up: HashMap<usize, Clade> = HashMap::with_capacity(t.len());
+    let mut up_cost: HashMap<usize, (usize, usize)> = HashMap::with_capacity(t.len());
+    for &n in order.iter().rev() {
+        let children = t[n].children();
+        for &c in children {
+            let others = children
+                .iter()
+                .filter(|&&o| o != c)
+                .map(|o| &down[o])
+                .chain(up.get(&n))
+                .collect::<Vec<_>>();
+            let mut clade = Clade::empty(species.len());
+            for o in others.iter() {
+                clade.merge(o, species_tree);
+            }
+            let mut total = add_costs(up_cost.get(&n).copied().unwrap_or_default(), cost(&others));
+            for o in children.iter().filter(|&&o| o != c) {
+                total = add_costs(total, down_cost[o]);
+            }
+            up.insert(c, clade);
+            up_cost.insert(c, total);
+        }
+    }
+
+    let (dups, losses) = down_cost[&root];
+    let mut best = (dups + losses, dups, None);
+    // Rerooting above a child of a binary root does not change the topology
+    let binary_root = t[root].children().len() == 2;
+    let mut todo = t[root].children().iter().rev().copied().collect::<Vec<_>>();
+    while let Some(n) = todo.pop() {
+        todo.extend(t[n].children().iter().rev());
+        if binary_root && t.parent(n) == Some(root) {
+            continue;
+        }
+        let (dups, losses) = add_costs(
+            add_costs(down_cost[&n], up_cost[&n]),
+            cost(&[&down[&n], &up[&n]]),
+        );
+        if (dups + losses, dups) < (best.0, best.1) {
+            best = (dups + losses, dups, Some(n));
+        }
+    }
+
+    if let Some(n) = best.2 {
+        let annotated = t
+            .inners()
+            .any(|n| t.attrs(n).contains_key("S") || t.attrs(n).contains_key("D"));
+        reroot_above(t, n);
+        for n in t.inners().collect::<Vec<_>>() {
+            for k in ["S", "D", "DCS", "ELC", "ELLC", "DLC"] {
+                t.attrs_mut(n).remove(k);
+            }
+        }
+        if annotated {
+            annotate_mrcas(t, species_tree)?;
+            annotate_duplications(t, species_tree, true, DuplicationMethod::Lca, 0., false)?;
+        }
+    }
+    let root = t.root();
+    t.attrs_mut(root)
+        .insert("DLC".to_owned(), best.0.to_string());
+    Ok(best.0)
+}
+
+/// Reroot `t` at the midpoint of its longest path between two leaves,
+/// missing branch lengths counting as zero. Single-child roots are removed.
+pub fn reroot_midpoint(t: &mut NewickTree) {
+    if t.is_empty() {
+        return;
+    }
+    // The farthest leaf from `from`, with, for each node, its neighbor
+    // toward `from`; a root with a single child is not a leaf, as its branch
+    // leads nowhere
+    fn farthest(t: &NewickTree, from: usize) -> (usize, f32, HashMap<usize, usize>) {
+        let length = |n: usize| t[n].branch().copied().unwrap_or(0.);
+        let mut best = (from, f32::NEG_INFINITY);
+        let mut toward = HashMap::new();
+        let mut todo = vec![(from, 0.)];
+        while let Some((n, d)) = todo.pop() {
+            if t[n].is_leaf() && d > best.1 {
+                best = (n, d);
+            }
+            let up = t.parent(n).filter(|_| !t.is_root(n));
+            for m in t[n].children().iter().copied().chain(up) {
+                if m != from && !toward.contains_key(&m) {
+                    toward.insert(m, n);
+                    let edge = if up == Some(m) { length(n) } else { length(m) };
+                    todo.push((m, d + edge));
+                }
+            }
+        }
+        (best.0, best.1, toward)
+    }
+
+    let (a, _, _) = farthest(t, t.root());
+    let (b, diameter, toward) = farthest(t, a);
+    if diameter <= 0. {
+        return;
+    }
+    // The path does not go through a root with a single child, that would
+    // be left dangling below the new root
+    while t[t.root()].children().len() == 1 {
+        let root = t.root();
+        let child = t[root].children()[0];
+        t.unplug(child);
+        t.delete_node(root);
+        t.set_root(child);
+        t[child].unset_branch();
+    }
+    // Walk from `b` toward `a` up to the middle of the path
+    let mut walked = 0.;
+    let mut n = b;
+    while let Some(&m) = toward.get(&n) {
+        let (child, parent) = if t.parent(n) == Some(m) && !t.is_root(n) {
+            (n, m)
+        } else {
+            (m, n)
+        };
+        let length = t[child].branch().copied().unwrap_or(0.);
+        if walked + length >= diameter / 2. {
+            let from_n = diameter / 2. - walked;
+            let below = if child == n { from_n } else { length - from_n };
+            if below >= length && t.is_root(parent) {
+                // The midpoint is already the root
+                return;
+            }
+            reroot_within(t, child, t[child].branch().map(|_| below));
+            return;
+        }
+        walked += length;
+        n = m;
+    }
+}
+
+/// Reroot `t` above the MRCA of the leaves named `outgroup`
+pub fn reroot_outgroup(t: &mut NewickTree, outgroup: &[String]) -> Result<(), AnnotationError> {
+    fn outgroup_mrca(t: &NewickTree, outgroup: &HashSet<&str>) -> usize {
+        t.mrca(t.leaves().filter(|&l| {
+            t.name(l)
+                .map(|name| outgroup.contains(name.as_str()))
+                .unwrap_or(false)
+        }))
+        .unwrap()
+    }
+
+    let names = outgroup.iter().map(String::as_str).collect::<HashSet<_>>();
+    let leaves = t.leaf_names().map(String::as_str).collect::<HashSet<_>>();
+    if let Some(missing) = names.iter().find(|name| !leaves.contains(*name)) {
+        return Err(AnnotationError::UnknownNode(missing.to_string()));
+    }
+    let mut mrca = outgroup_mrca(t, &names);
+    if t.is_root(mrca) {
+        // The outgroup spans the root: first move the root into the ingroup
+        let ingroup = t.leaves().find(|&l| {
+            t.name(l)
+                .map(|name| !names.contains(name.as_str()))
+                .unwrap_or(true)
+        });
+        if let Some(ingroup) = ingroup {
+            reroot_above(t, ingroup);
+            mrca = outgroup_mrca(t, &names);
+        }
+    }
+    reroot_above(t, mrca);
     Ok(())
 }
 
-pub fn speciesize(t: &mut NewickTree, book: &mut GeneBook) -> Result<()> {
-    let leaves = t.leaves().collect::<Vec<_>>();
-    for l in leaves.into_iter() {
-        if t[l].data().name.is_some() {
-            let name = t[l].data().name.as_ref().unwrap().to_owned();
-            t[l].data_mut().attrs.insert(
-                "S".to_owned(),
-                book.get(&name)
-                    .unwrap_or_else(|_| panic!("Cannot find {:?}", name))
-                    .species,
-            );
+/// Reroot `t` above the node named `name`
+pub fn reroot_node(t: &mut NewickTree, name: &str) -> Result<(), AnnotationError> {
+    let n = t
+        .find_node(|d| d.name.as_deref() == Some(name))
+        .ok_or_else(|| AnnotationError::UnknownNode(name.to_owned()))?;
+    reroot_above(t, n);
+    Ok(())
+}
+
+/// List all the pairs of genes of a tree annotated by [`annotate_mrcas`] and
+/// [`annotate_duplications`] as TSV rows, with their species, their
+/// relationship (ortholog or paralog) and the taxon of their LCA.
+///
+/// Loss leaves inserted by [`reconcile`] are ignored.
+pub fn orthologs(t: &NewickTree) -> Result<String, AnnotationError> {
+    fn genes_of(t: &NewickTree, n: usize) -> Vec<usize> {
+        let mut r = Vec::new();
+        let mut todo = vec![n];
+        while let Some(n) = todo.pop() {
+            if t[n].is_leaf() {
+                if t.name(n).is_some() && !is_loss(t, n) {
+                    r.push(n);
+                }
+            } else {
+                todo.extend(t[n].children().iter().rev());
+            }
         }
+        r
+    }
+
+    fn rec_orthologs(t: &NewickTree, n: usize, ax: &mut String) -> Result<(), AnnotationError> {
+        let children = t[n].children();
+        if children.len() >= 2 {
+            let relation = match t.attrs(n).get("D").map(|d| d.as_str()) {
+                Some("Y") => "paralog",
+                Some("N") => "ortholog",
+                _ => {
+                    return Err(AnnotationError::MissingDuplication {
+                        node: node_name(t, n),
+                    })
+                }
+            };
+            let lca_taxon = t.attrs(n).get("S").map(|s| s.as_str()).unwrap_or_default();
+            let genes = children.iter().map(|&c| genes_of(t, c)).collect::<Vec<_>>();
+            for (i, left) in genes.iter().enumerate() {
+                for right in genes.iter().skip(i + 1) {
+                    for &a in left.iter() {
+                        for &b in right.iter() {
+                            ax.push_str(&format!(
+                                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                                t.name(a).unwrap(),
+                                t.name(b).unwrap(),
+                                t.attrs(a).get("S").map(|s| s.as_str()).unwrap_or_default(),
+                                t.attrs(b).get("S").map(|s| s.as_str()).unwrap_or_default(),
+                                relation,
+                                lca_taxon
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        for &c in children {
+            rec_orthologs(t, c, ax)?;
+        }
+        Ok(())
+    }
+
+    let mut r = String::new();
+    rec_orthologs(t, t.root(), &mut r)?;
+    Ok(r)
+}
+
+/// Whether `n` is a loss leaf inserted by [`reconcile`]
+pub fn is_loss(t: &NewickTree, n: usize) -> bool {
+    t.attrs(n).get("L").map(|l| l.as_str()) == Some("Y")
+}
+
+/// Split a tree annotated by [`annotate_mrcas`] and [`annotate_duplications`]
+/// into the orthogroups descending from the ancestral taxon `level` of
+/// `species_tree`, i.e. the maximal subtrees without any duplication at or
+/// above `level`.
+///
+/// Genes from lineages outside of `level` are discarded, as well as the
+/// orthogroups only made of losses.
+pub fn split_orthogroups(
+    t: &NewickTree,
+    species_tree: &SpeciesTree,
+    level: &str,
+) -> Result<Vec<NewickTree>, AnnotationError> {
+    fn rec_split(
+        t: &NewickTree,
+        n: usize,
+        species_tree: &SpeciesTree,
+        level: usize,
+        ax: &mut Vec<usize>,
+    ) -> Result<(), AnnotationError> {
+        let species = species_of(t, n, species_tree)?;
+
+        if species_tree.is_ancestor(level, species) {
+            if species == level && t.is_duplication(n) {
+                for &c in t[n].children() {
+                    rec_split(t, c, species_tree, level, ax)?;
+                }
+            } else {
+                ax.push(n);
+            }
+        } else if species_tree.is_ancestor(species, level) {
+            for &c in t[n].children() {
+                rec_split(t, c, species_tree, level, ax)?;
+            }
+        }
+        Ok(())
+    }
+
+    let level = species_tree
+        .node(level)
+        .ok_or_else(|| AnnotationError::UnknownTaxon(level.to_owned()))?;
+    let mut roots = Vec::new();
+    rec_split(t, t.root(), species_tree, level, &mut roots)?;
+
+    Ok(roots
+        .into_iter()
+        .filter(|&n| {
+            t.leaves_of(n)
+                .into_iter()
+                .chain(std::iter::once(n))
+                .any(|l| t[l].is_leaf() && !is_loss(t, l))
+        })
+        .map(|n| extract_subtree(t, n))
+        .collect())
+}
+
+pub fn speciesize(t: &mut NewickTree, book: &GeneBook) -> Result<(), AnnotationError> {
+    let species = t
+        .leaves()
+        .filter_map(|l| t.name(l).map(|name| (l, name)))
+        .map(|(l, name)| {
+            book.get(name)
+                .map(|gene| (l, gene.species))
+                .map_err(|e| AnnotationError::UnknownGene {
+                    gene: name.to_owned(),
+                    reason: e.to_string(),
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (l, species) in species.into_iter() {
+        t.attrs_mut(l).insert("S".to_owned(), species);
     }
 
     Ok(())
 }
 
-pub fn taxonize(t: &mut NewickTree, map_file: &str) -> Result<()> {
-    let map = BufReader::new(File::open(map_file)?)
-        .lines()
+/// Read a tab-separated taxon ID/species name mapping
+pub fn taxon_mapping(src: impl BufRead) -> HashMap<usize, String> {
+    src.lines()
         .filter_map(|l| {
             l.ok().and_then(|l| {
                 let mut s = l.split('\t');
@@ -143,8 +757,10 @@ pub fn taxonize(t: &mut NewickTree, map_file: &str) -> Result<()> {
                 Some((src, tgt))
             })
         })
-        .collect::<HashMap<usize, String>>();
+        .collect::<HashMap<usize, String>>()
+}
 
+pub fn taxonize(t: &mut NewickTree, map: &HashMap<usize, String>) {
     for l in t.nodes_mut() {
         let taxon_id = l
             .data_mut()
@@ -163,35 +779,332 @@ pub fn taxonize(t: &mut NewickTree, map_file: &str) -> Result<()> {
             eprintln!("Node `{:?}` has no taxon specified", l.data().name);
         }
     }
-    Ok(())
 }
 
-pub fn compress(t: &mut NewickTree) -> Result<()> {
+pub fn compress(t: &mut NewickTree) {
     while t[t.root()].children().len() == 1 {
-        println!("Compressing");
+        eprintln!("Compressing");
         t.set_root(t[t.root()].children()[0]);
     }
+}
+
+/// The criteria selecting the nodes to prune; a node is selected if it
+/// matches any of them
+pub struct PruneSelector {
+    /// the exact names of the nodes
+    pub names: HashSet<String>,
+    /// a pattern matching the names of the nodes
+    pub regex: Option<Regex>,
+    /// NHX (attribute, value) pairs
+    pub attrs: Vec<(String, String)>,
+}
+impl PruneSelector {
+    fn matches(&self, t: &NewickTree, n: usize) -> bool {
+        t.name(n)
+            .map(|name| {
+                self.names.contains(name)
+                    || self
+                        .regex
+                        .as_ref()
+                        .map(|r| r.is_match(name))
+                        .unwrap_or(false)
+            })
+            .unwrap_or(false)
+            || self.attrs.iter().any(|(k, v)| t.attrs(n).get(k) == Some(v))
+    }
+
+    /// The selected nodes of `t` that are not below another selected one, in
+    /// pre-order
+    pub fn select(&self, t: &NewickTree) -> Vec<usize> {
+        let mut r = Vec::new();
+        if t.is_empty() {
+            return r;
+        }
+        let mut todo = vec![t.root()];
+        while let Some(n) = todo.pop() {
+            if self.matches(t, n) {
+                r.push(n);
+            } else {
+                todo.extend(t[n].children().iter().rev());
+            }
+        }
+        r
+    }
+}
+
+/// Parse an attribute predicate formatted as `key=value`
+pub fn parse_attr_predicate(predicate: &str) -> Result<(String, String)> {
+    predicate
+        .split_once('=')
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .with_context(|| format!("`{}` is not formatted as `key=value`", predicate))
+}
+
+/// Remove from `t` the nodes selected by `selector` along with their
+/// descendants, then the unnamed nodes left empty or with a single child, the
+/// branch lengths of the latter being added to the one of their child
+pub fn prune(t: &mut NewickTree, selector: &PruneSelector) {
+    if t.is_empty() {
+        return;
+    }
+    let selected = selector.select(t).into_iter().collect::<HashSet<_>>();
+    let mut kept = HashSet::new();
+    for n in postorder(t) {
+        if !selected.contains(&n)
+            && (t.name(n).is_some() || t[n].children().iter().any(|c| kept.contains(c)))
+        {
+            kept.insert(n);
+        }
+    }
+    *t = copy_kept(t, &kept, |n| t.name(n).is_none());
+}
+
+/// Copy the nodes of `t` found in `kept`, descending from the root through
+/// them only. The copied nodes left with a single child are suppressed if
+/// `suppress` holds for them, their branch length being added to the one of
+/// their child.
+fn copy_kept(
+    t: &NewickTree,
+    kept: &HashSet<usize>,
+    suppress: impl Fn(usize) -> bool,
+) -> NewickTree {
+    let mut r = NewickTree::new();
+    if t.is_empty() || !kept.contains(&t.root()) {
+        return r;
+    }
+    // node, parent of its copy, branch length carried from suppressed nodes
+    let mut todo = vec![(t.root(), None, None)];
+    while let Some((n, parent, carried)) = todo.pop() {
+        let length = match (carried, t[n].branch()) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0.) + b.copied().unwrap_or(0.)),
+        };
+        let children = t[n]
+            .children()
+            .iter()
+            .copied()
+            .filter(|c| kept.contains(c))
+            .collect::<Vec<_>>();
+        if children.len() == 1 && suppress(n) {
+            todo.push((children[0], parent, length));
+        } else {
+            let new = r.add_node(
+                parent,
+                Data {
+                    name: t.name(n).cloned(),
+                    attrs: t.attrs(n).clone(),
+                },
+            );
+            if let Some(l) = length {
+                r[new].set_branch(l);
+            }
+            // Children are pushed in reverse to be created in order
+            todo.extend(children.into_iter().rev().map(|c| (c, Some(new), None)));
+        }
+    }
+    r
+}
+
+/// Restrict `t` to the subtree induced by the leaves named in `keep`: all
+/// other leaves are removed, then the nodes left with a single child are
+/// suppressed, their branch length being added to the one of their child.
+/// The branch above the new root is dropped.
+pub fn restrict(t: &mut NewickTree, keep: &HashSet<String>) -> Result<(), AnnotationError> {
+    if t.is_empty() {
+        return Ok(());
+    }
+    let mut kept = HashSet::new();
+    for n in postorder(t) {
+        let keep = if t[n].is_leaf() {
+            t.name(n).map(|name| keep.contains(name)).unwrap_or(false)
+        } else {
+            t[n].children().iter().any(|c| kept.contains(c))
+        };
+        if keep {
+            kept.insert(n);
+        }
+    }
+    if !kept.contains(&t.root()) {
+        return Err(AnnotationError::NoLeavesKept);
+    }
+
+    let mut r = copy_kept(t, &kept, |_| true);
+    let root = r.root();
+    r[root].unset_branch();
+    *t = r;
     Ok(())
 }
 
-pub fn to_phy(t: &NewickTree) -> Result<String> {
-    fn rec_to_phy(ax: &mut String, t: &NewickTree, n: usize, d: usize, id: usize) {
-        ax.push_str(&format!(
-            "{}{}\n",
-            "\t".repeat(d),
-            t.name(n).cloned().unwrap_or_else(|| String::from("UKNWN"))
-        ));
+/// How to write trees in the phyl format, see [`to_phy`]
+pub struct PhyOptions {
+    /// whether to write the branch lengths
+    pub lengths: bool,
+    /// the attributes to write
+    pub attrs: Vec<String>,
+    /// the name given to unnamed nodes
+    pub placeholder: String,
+    /// whether to suffix the placeholder with the pre-order index of the node,
+    /// as `<placeholder>_<index>`
+    pub ids: bool,
+}
+
+/// Whether `name` marks an unnamed node in the phyl format, i.e. is
+/// `placeholder`, or, if `ids` is set, `<placeholder>_<index>`
+fn is_phy_placeholder(name: &str, placeholder: &str, ids: bool) -> bool {
+    name == placeholder
+        || (ids
+            && name
+                .strip_prefix(placeholder)
+                .and_then(|rest| rest.strip_prefix('_'))
+                .map(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+                .unwrap_or(false))
+}
+
+/// Convert `t` to the phyl format, see [`from_phy`], following `options`.
+/// Fails if a named node would be read back as unnamed.
+pub fn to_phy(t: &NewickTree, options: &PhyOptions) -> Result<String> {
+    if let Some(name) = t
+        .nodes()
+        .filter_map(|n| t.name(n))
+        .find(|name| is_phy_placeholder(name, &options.placeholder, options.ids))
+    {
+        bail!(
+            "node `{}` would be read back as unnamed; use another placeholder",
+            name
+        );
+    }
+
+    fn rec_to_phy(
+        ax: &mut String,
+        t: &NewickTree,
+        n: usize,
+        d: usize,
+        id: &mut usize,
+        options: &PhyOptions,
+    ) {
+        ax.push_str(&"\t".repeat(d));
+        match t.name(n) {
+            Some(name) => ax.push_str(name),
+            None if options.ids => ax.push_str(&format!("{}_{}", options.placeholder, id)),
+            None => ax.push_str(&options.placeholder),
+        }
+        if options.lengths {
+            if let Some(length) = t[n].branch() {
+                ax.push_str(&format!("\t:{}", length));
+            }
+        }
+        for k in options.attrs.iter() {
+            if let Some(v) = t.attrs(n).get(k) {
+                ax.push_str(&format!("\t{}={}", k, v));
+            }
+        }
+        ax.push('\n');
+
+        *id += 1;
         for c in t[n].children() {
-            rec_to_phy(ax, t, *c, d + 1, id);
+            rec_to_phy(ax, t, *c, d + 1, id, options);
         }
     }
 
     let mut r = String::new();
     let root = t.root();
-    rec_to_phy(&mut r, t, root, 0, 0);
+    rec_to_phy(&mut r, t, root, 0, &mut 0, options);
     Ok(r)
 }
 
+/// Convert `t` to a GraphViz graph named `name`. Nodes are labeled with their
+/// name and species, duplications are drawn as red boxes, losses as dashed
+/// grey nodes, and edges are labeled with the branch lengths.
+pub fn to_dot(t: &NewickTree, name: &str) -> String {
+    fn escape(s: &str) -> String {
+        s.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    let mut r = format!(
+        "digraph \"{}\" {{\n  rankdir=LR;\n  node [fontname=\"Helvetica\"];\n",
+        escape(name)
+    );
+    for n in t.nodes() {
+        let label = [t.name(n), t.attrs(n).get("S")]
+            .iter()
+            .flatten()
+            .map(|s| escape(s))
+            .collect::<Vec<_>>()
+            .join("\\n");
+        let style = if is_loss(t, n) {
+            "shape=plaintext, style=dashed, fontcolor=grey"
+        } else if t.is_duplication(n) {
+            "shape=box, style=filled, fillcolor=\"#e41a1c\", fontcolor=white"
+        } else if t[n].is_leaf() {
+            "shape=plaintext"
+        } else if label.is_empty() {
+            "shape=point"
+        } else {
+            "shape=ellipse"
+        };
+        r.push_str(&format!("  n{} [label=\"{}\", {}];\n", n, label, style));
+    }
+    for n in t.nodes() {
+        for &c in t[n].children() {
+            r.push_str(&format!("  n{} -> n{}", n, c));
+            if let Some(length) = t[c].branch() {
+                r.push_str(&format!(" [label=\"{}\"]", length));
+            }
+            r.push_str(";\n");
+        }
+    }
+    r.push_str("}\n");
+    r
+}
+
+/// Rebuild a tree from its phyl representation, where each line holds a node
+/// indented by as many tabs as its depth. The name of a node may be followed
+/// by tab-separated fields, either its branch length as `:<length>` or
+/// attributes as `<key>=<value>`. Nodes named `placeholder`, or
+/// `<placeholder>_<index>` if `ids` is set, are left unnamed.
+pub fn from_phy(phy: &str, placeholder: &str, ids: bool) -> Result<NewickTree> {
+    let mut t = NewickTree::new();
+    // the current node at each depth
+    let mut path: Vec<usize> = Vec::new();
+    for (i, line) in phy.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let depth = line.chars().take_while(|&c| c == '\t').count();
+        if depth > path.len() || (depth == 0 && !path.is_empty()) {
+            bail!("line {}: invalid indentation", i + 1);
+        }
+        path.truncate(depth);
+
+        let mut fields = line[depth..].split('\t');
+        let name = fields
+            .next()
+            .filter(|name| !name.is_empty() && !is_phy_placeholder(name, placeholder, ids));
+        let n = t.add_node(
+            path.last().copied(),
+            newick::Data {
+                name: name.map(str::to_owned),
+                attrs: Default::default(),
+            },
+        );
+        for field in fields.filter(|f| !f.is_empty()) {
+            if let Some(length) = field.strip_prefix(':') {
+                t[n].set_branch(
+                    length
+                        .parse()
+                        .with_context(|| format!("line {}: invalid length `{}`", i + 1, length))?,
+                );
+            } else if let Some((k, v)) = field.split_once('=') {
+                t.attrs_mut(n).insert(k.to_owned(), v.to_owned());
+            } else {
+                bail!("line {}: invalid field `{}`", i + 1, field);
+            }
+        }
+        path.push(n);
+    }
+    Ok(t)
+}
+
 pub fn normalize(t: &mut NewickTree) {
     let mut known_names = HashSet::new();
 
@@ -276,3 +1189,149 @@ pub fn sort(t: &mut NewickTree, leaves_only: bool) {
         t.sort_by(|x| x.name.as_ref().cloned().unwrap_or_default())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_newick(t: &NewickTree) -> String {
+        Newick::to_newick(t, false)
+    }
+
+    #[test]
+    fn reconcile_inserts_speciations_and_losses_in_place() {
+        // Inner nodes are shown by their species
+        fn shape(t: &NewickTree, n: usize) -> String {
+            if t[n].is_leaf() {
+                t.name(n).cloned().unwrap_or_default()
+            } else {
+                format!(
+                    "({}){}",
+                    t[n].children()
+                        .iter()
+                        .map(|&c| shape(t, c))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    t.attrs(n).get("S").cloned().unwrap_or_default()
+                )
+            }
+        }
+
+        let species_tree = SpeciesTree::new(
+            newick::one_from_string("(((Hs,Pt)Hominini,Gg)Homininae,Mm)Euarchontoglires;").unwrap(),
+        );
+        let mut t =
+            newick::one_from_string("(h1[&&NHX:S=Hs],(m1[&&NHX:S=Mm],g1[&&NHX:S=Gg]));").unwrap();
+        annotate_mrcas(&mut t, &species_tree).unwrap();
+        annotate_duplications(
+            &mut t,
+            &species_tree,
+            true,
+            DuplicationMethod::Lca,
+            0.,
+            false,
+        )
+        .unwrap();
+        reconcile(&mut t, &species_tree, true).unwrap();
+        // Pt is absent from the gene tree, so its loss is not reported
+        assert_eq!(
+            shape(&t, t.root()),
+            "(((h1,Gg*LOST)Homininae,Mm*LOST)Euarchontoglires,\
+             (m1,(g1,Hominini*LOST)Homininae)Euarchontoglires)Euarchontoglires"
+        );
+        assert!(t.is_duplication(t.root()));
+        let lost = t.find_node(|n| n.name.as_deref() == Some("Mm*LOST"));
+        assert_eq!(
+            t.attrs(lost.unwrap()).get("L").map(String::as_str),
+            Some("Y")
+        );
+    }
+
+    #[test]
+    fn prune_collapses_the_unary_nodes() {
+        let selector = |names: &[&str]| PruneSelector {
+            names: names.iter().map(|&s| s.to_owned()).collect(),
+            regex: None,
+            attrs: Vec::new(),
+        };
+        let mut t = newick::one_from_string("((h1,m1),(h2,p2));").unwrap();
+        prune(&mut t, &selector(&["h1", "m1"]));
+        assert_eq!(to_newick(&t), "(h2,p2);");
+
+        let mut t = newick::one_from_string("((a:1,b:2):1,c:3);").unwrap();
+        prune(&mut t, &selector(&["a"]));
+        assert_eq!(to_newick(&t), "(b:3,c:3);");
+    }
+
+    #[test]
+    fn reroot_midpoint_splits_the_longest_path() {
+        // D and B are 15 apart, so the root goes 7.5 above D
+        let mut t = newick::one_from_string("((A:1,B:2)X:1,(C:3,D:10)Y:2)R;").unwrap();
+        reroot_midpoint(&mut t);
+        assert_eq!(to_newick(&t), "(D:7.5,(C:3,(A:1,B:2)X:3)Y:2.5);");
+
+        let mut t = newick::one_from_string("((A:1,B:2,C:3)X:1)R;").unwrap();
+        reroot_midpoint(&mut t);
+        assert_eq!(to_newick(&t), "(C:2.5,(A:1,B:2)X:0.5);");
+
+        // The branch above a single-child root does not lead to any leaf
+        let mut t = newick::one_from_string("(((A:1,B:1)X:1,C:1)Y:10);").unwrap();
+        reroot_midpoint(&mut t);
+        assert_eq!(to_newick(&t), "((A:1,B:1)X:0.5,C:1.5);");
+
+        // Nor does it move the midpoint away from the single child
+        let mut t = newick::one_from_string("((A:1,B:1)X:5);").unwrap();
+        reroot_midpoint(&mut t);
+        assert_eq!(to_newick(&t), "(A:1,B:1)X;");
+    }
+
+    #[test]
+    fn reroot_outgroup_roots_above_the_mrca() {
+        let mut t = newick::one_from_string("((A:1,B:2)X:1,(C:3,D:10)Y:2)R;").unwrap();
+        reroot_outgroup(&mut t, &[String::from("D")]).unwrap();
+        assert_eq!(to_newick(&t), "(D:5,(C:3,(A:1,B:2)X:3)Y:5);");
+
+        // The root is only moved along its branch
+        let mut t = newick::one_from_string("((A:1,B:2)X:1,(C:3,D:10)Y:2)R;").unwrap();
+        reroot_outgroup(&mut t, &[String::from("C"), String::from("D")]).unwrap();
+        assert_eq!(to_newick(&t), "((A:1,B:2)X:1.5,(C:3,D:10)Y:1.5)R;");
+
+        // The outgroup spans the original root
+        let mut t = newick::one_from_string("((A:1,B:2)X:1,(C:3,D:10)Y:2)R;").unwrap();
+        let outgroup = ["A", "B", "C"]
+            .iter()
+            .map(|&s| s.to_owned())
+            .collect::<Vec<_>>();
+        reroot_outgroup(&mut t, &outgroup).unwrap();
+        assert_eq!(to_newick(&t), "(D:5,(C:3,(A:1,B:2)X:3)Y:5);");
+
+        assert!(reroot_outgroup(&mut t, &[String::from("Z")]).is_err());
+    }
+
+    #[test]
+    fn phy_round_trips_indexed_placeholders() {
+        let newick = "((A:1,B:2):1,(C:3,UNKNOWN_7:10)Y:2);";
+        let t = newick::one_from_string(newick).unwrap();
+        let options = |ids| PhyOptions {
+            lengths: true,
+            attrs: Vec::new(),
+            placeholder: String::from("UNKNOWN"),
+            ids,
+        };
+
+        // Without indices, `UNKNOWN_7` can not be mistaken for a placeholder
+        let phy = to_phy(&t, &options(false)).unwrap();
+        assert!(phy.starts_with("UNKNOWN\n\tUNKNOWN\t:1\n"));
+        let back = from_phy(&phy, "UNKNOWN", false).unwrap();
+        assert_eq!(to_newick(&back), newick);
+
+        // With indices, it would be read back as unnamed
+        assert!(to_phy(&t, &options(true)).is_err());
+
+        let t = newick::one_from_string("((A:1,B:2):1,(C:3,D:10)Y:2);").unwrap();
+        let phy = to_phy(&t, &options(true)).unwrap();
+        assert!(phy.starts_with("UNKNOWN_0\n\tUNKNOWN_1\t:1\n"));
+        let back = from_phy(&phy, "UNKNOWN", true).unwrap();
+        assert_eq!(to_newick(&back), to_newick(&t));
+    }
+}
@@ -0,0 +1,97 @@
+use clap::ValueEnum;
+use newick::{Newick, NewickTree};
+use thiserror::Error;
+
+use crate::utils::extract_subtree;
+
+#[derive(Error, Debug)]
+pub enum AnnotationError {
+    #[error("`{node}` is not annotated with a species")]
+    MissingSpecies { node: String },
+
+    #[error("species `{species}` of `{node}` not found in the species tree")]
+    UnknownSpecies { node: String, species: String },
+
+    #[error("taxon `{0}` not found in the species tree")]
+    UnknownTaxon(String),
+
+    #[error("unnamed node found in the species tree")]
+    UnnamedSpecies,
+
+    #[error("species `{ancestor}` of `{node}` is not an ancestor of `{species}`")]
+    InconsistentSpecies {
+        node: String,
+        species: String,
+        ancestor: String,
+    },
+
+    #[error("`{node}` is not annotated with duplication information")]
+    MissingDuplication { node: String },
+
+    #[error("node `{0}` not found")]
+    UnknownNode(String),
+
+    #[error("none of the leaves to keep found")]
+    NoLeavesKept,
+
+    #[error("cannot find the species of `{gene}`: {reason}")]
+    UnknownGene { gene: String, reason: String },
+}
+
+/// An [`AnnotationError`] located in a multi-trees file
+#[derive(Error, Debug)]
+#[error("in tree #{index}")]
+pub struct TreeError {
+    pub index: usize,
+    #[source]
+    pub source: AnnotationError,
+}
+
+/// A human-readable name for the node `n` of `t`, to be used in error messages
+pub fn node_name(t: &NewickTree, n: usize) -> String {
+    t.name(n)
+        .cloned()
+        .unwrap_or_else(|| String::from("<unnamed>"))
+}
+
+/// What to do with trees that can not be processed
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OnError {
+    /// drop the tree from the output and report it
+    Skip,
+    /// output the tree unchanged and report it
+    Keep,
+    /// abort the whole process
+    Fail,
+}
+impl OnError {
+    /// Apply `f` to `t`, the `index`-th tree of a file, then handle its
+    /// possible failure according to this policy. Returns whether `t` should
+    /// be written out.
+    pub fn apply<F>(&self, index: usize, t: &mut NewickTree, f: F) -> Result<bool, TreeError>
+    where
+        F: FnOnce(&mut NewickTree) -> Result<(), AnnotationError>,
+    {
+        let original = if let OnError::Keep = self {
+            Some(extract_subtree(t, t.root()))
+        } else {
+            None
+        };
+
+        match f(t) {
+            Ok(_) => Ok(true),
+            Err(source) => match self {
+                OnError::Skip => {
+                    eprintln!("skipping tree #{}: {}", index, source);
+                    Ok(false)
+                }
+                OnError::Keep => {
+                    eprintln!("keeping tree #{} unchanged: {}", index, source);
+                    *t = original.unwrap();
+                    Ok(true)
+                }
+                OnError::Fail => Err(TreeError { index, source }),
+            },
+        }
+    }
+}
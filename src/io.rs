@@ -0,0 +1,505 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use newick::{Newick, NewickTree};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::{json, nexus, phyloxml};
+
+/// The file name standing for the standard input or output
+pub const STDIO: &str = "-";
+
+/// The compression formats supported for input and output files
+#[derive(Clone, Copy)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+impl Compression {
+    fn from_magic(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if header.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    fn from_extension(filename: &str) -> Self {
+        match Path::new(filename).extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            _ => Compression::None,
+        }
+    }
+
+    /// Wrap `src`, decompressing it if required
+    fn decoder(self, src: Box<dyn BufRead>) -> Result<Box<dyn BufRead>> {
+        Ok(match self {
+            Compression::None => src,
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(src))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(src)?)),
+            Compression::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(src))),
+        })
+    }
+
+    /// Wrap `out`, compressing it if required
+    fn encoder(self, out: Box<dyn Write>) -> Result<Encoder> {
+        Ok(match self {
+            Compression::None => Encoder::Plain(out),
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(out, flate2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(out, 0)?),
+            Compression::Bzip2 => {
+                Encoder::Bzip2(BzEncoder::new(out, bzip2::Compression::default()))
+            }
+        })
+    }
+}
+
+/// A writer compressing its output on the fly; it must be explicitly
+/// finished to be complete
+enum Encoder {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+    Bzip2(BzEncoder<Box<dyn Write>>),
+}
+impl Encoder {
+    /// Write the compression trailers, if any, and flush the underlying writer
+    fn finish(self) -> std::io::Result<()> {
+        let mut out = match self {
+            Encoder::Plain(out) => out,
+            Encoder::Gzip(e) => e.finish()?,
+            Encoder::Zstd(e) => e.finish()?,
+            Encoder::Bzip2(e) => e.finish()?,
+        };
+        out.flush()
+    }
+}
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Plain(out) => out.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::Bzip2(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Plain(out) => out.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::Bzip2(e) => e.flush(),
+        }
+    }
+}
+
+/// Open `filename`, or the standard input if it is `-`, transparently
+/// decompressing it if its content or its extension says so
+pub fn open(filename: &str) -> Result<Box<dyn BufRead>> {
+    let mut src: Box<dyn BufRead> = if filename == STDIO {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        Box::new(BufReader::new(
+            File::open(filename).with_context(|| format!("cannot open `{}`", filename))?,
+        ))
+    };
+    let header = src
+        .fill_buf()
+        .with_context(|| format!("cannot read from `{}`", filename))?;
+    let compression =
+        Compression::from_magic(header).unwrap_or_else(|| Compression::from_extension(filename));
+    compression
+        .decoder(src)
+        .with_context(|| format!("cannot decompress `{}`", filename))
+}
+
+/// Parse the single tree contained in `filename`
+pub fn read_tree(filename: &str) -> Result<NewickTree> {
+    let mut content = String::new();
+    open(filename)?
+        .read_to_string(&mut content)
+        .with_context(|| format!("cannot read from `{}`", filename))?;
+    newick::one_from_string(&content).with_context(|| format!("while parsing {}", filename))
+}
+
+/// Read the non-empty lines of `filename`, e.g. a list of node names
+pub fn read_names(filename: &str) -> Result<HashSet<String>> {
+    let mut r = HashSet::new();
+    for l in open(filename)?.lines() {
+        let l = l.with_context(|| format!("cannot read from `{}`", filename))?;
+        if !l.trim().is_empty() {
+            r.insert(l.trim().to_owned());
+        }
+    }
+    Ok(r)
+}
+
+/// The supported tree file formats
+#[derive(Clone, Copy, ValueEnum)]
+pub enum TreeFormat {
+    Newick,
+    Nexus,
+    Phyloxml,
+    Json,
+}
+impl TreeFormat {
+    /// What must precede the first tree of a file
+    fn header(self) -> &'static str {
+        match self {
+            TreeFormat::Newick | TreeFormat::Json => "",
+            TreeFormat::Nexus => nexus::HEADER,
+            TreeFormat::Phyloxml => phyloxml::HEADER,
+        }
+    }
+
+    /// What must follow the last tree of a file
+    fn footer(self) -> &'static str {
+        match self {
+            TreeFormat::Newick | TreeFormat::Json => "",
+            TreeFormat::Nexus => nexus::FOOTER,
+            TreeFormat::Phyloxml => phyloxml::FOOTER,
+        }
+    }
+}
+
+/// Read the next Newick tree from `src`
+fn next_newick(src: &mut dyn BufRead, buffer: &mut Vec<u8>) -> Result<Option<NewickTree>> {
+    loop {
+        // `;` can only appear as a tree terminator in the NHX grammar
+        buffer.clear();
+        if src.read_until(b';', buffer)? == 0 {
+            return Ok(None);
+        }
+        let chunk = std::str::from_utf8(buffer)?;
+        if chunk.trim().is_empty() {
+            continue;
+        }
+        if let Some(t) = newick::from_string(chunk)?.into_iter().next() {
+            return Ok(Some(t));
+        }
+    }
+}
+
+/// Read the next phyl tree from `src`; trees are separated either by blank
+/// lines or by a new root, which is then kept in `pending`
+fn next_phy(
+    src: &mut dyn BufRead,
+    pending: &mut Option<String>,
+    placeholder: &str,
+    ids: bool,
+) -> Result<Option<NewickTree>> {
+    let mut phy = pending.take().unwrap_or_default();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if src.read_line(&mut line)? == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            if phy.is_empty() {
+                continue;
+            } else {
+                break;
+            }
+        }
+        if !line.starts_with('\t') && !phy.is_empty() {
+            *pending = Some(line);
+            break;
+        }
+        phy.push_str(&line);
+    }
+
+    if phy.is_empty() {
+        Ok(None)
+    } else {
+        crate::actions::from_phy(&phy, placeholder, ids).map(Some)
+    }
+}
+
+enum Source {
+    Newick {
+        src: Box<dyn BufRead>,
+        buffer: Vec<u8>,
+    },
+    Nexus(nexus::Reader),
+    Phyloxml(phyloxml::Reader),
+    Json(json::Reader),
+    Phy {
+        src: Box<dyn BufRead>,
+        pending: Option<String>,
+        placeholder: String,
+        ids: bool,
+    },
+}
+
+/// A reader lazily parsing the trees of a file one at a time, so that at most
+/// one of them has to be held in memory
+pub struct Trees {
+    name: String,
+    source: Source,
+    count: usize,
+}
+impl Iterator for Trees {
+    type Item = Result<NewickTree>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = match &mut self.source {
+            Source::Newick { src, buffer } => next_newick(src.as_mut(), buffer),
+            Source::Nexus(reader) => reader.next_tree(),
+            Source::Phyloxml(reader) => reader.next_tree(),
+            Source::Json(reader) => reader.next_tree(),
+            Source::Phy {
+                src,
+                pending,
+                placeholder,
+                ids,
+            } => next_phy(src.as_mut(), pending, placeholder, *ids),
+        };
+        match next {
+            Ok(Some(t)) => {
+                self.count += 1;
+                Some(Ok(t))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                Some(Err(e).with_context(|| {
+                    format!("failed to parse tree #{} of {}", self.count, self.name)
+                }))
+            }
+        }
+    }
+}
+
+fn source_name(infile: &str) -> String {
+    if infile == STDIO {
+        "stdin".into()
+    } else {
+        infile.to_owned()
+    }
+}
+
+/// Lazily read the trees from `infile`, or from the standard input if it is `-`
+pub fn read_trees(infile: &str, format: TreeFormat) -> Result<Trees> {
+    let src = open(infile)?;
+    Ok(Trees {
+        name: source_name(infile),
+        source: match format {
+            TreeFormat::Newick => Source::Newick {
+                src,
+                buffer: Vec::new(),
+            },
+            TreeFormat::Nexus => Source::Nexus(nexus::Reader::new(src)),
+            TreeFormat::Phyloxml => Source::Phyloxml(phyloxml::Reader::new(src)),
+            TreeFormat::Json => Source::Json(json::Reader::new(src)),
+        },
+        count: 0,
+    })
+}
+
+/// Lazily read the phyl-formatted trees from `infile`, or from the standard
+/// input if it is `-`, leaving the nodes named `placeholder`, or
+/// `<placeholder>_<index>` if `ids` is set, unnamed
+pub fn read_phy_trees(infile: &str, placeholder: &str, ids: bool) -> Result<Trees> {
+    Ok(Trees {
+        name: source_name(infile),
+        source: Source::Phy {
+            src: open(infile)?,
+            pending: None,
+            placeholder: placeholder.to_owned(),
+            ids,
+        },
+        count: 0,
+    })
+}
+
+/// How the output file should be handled when it could overwrite something
+pub struct OutputPolicy {
+    /// whether the input file may be overwritten
+    pub in_place: bool,
+    /// whether a `.bak` copy of an overwritten file should be kept
+    pub backup: bool,
+}
+
+/// A file being written to a temporary location, to be atomically moved to
+/// its final destination once complete
+struct Staging {
+    tmp: PathBuf,
+    target: PathBuf,
+    backup: bool,
+    done: bool,
+}
+impl Staging {
+    fn new(target: &str, backup: bool) -> Result<Self> {
+        let target = PathBuf::from(target);
+        let file_name = target
+            .file_name()
+            .and_then(|f| f.to_str())
+            .with_context(|| format!("invalid filename: `{}`", target.display()))?;
+        let tmp = target.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+        Ok(Staging {
+            tmp,
+            target,
+            backup,
+            done: false,
+        })
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.backup && self.target.exists() {
+            let mut bak = self.target.clone().into_os_string();
+            bak.push(".bak");
+            std::fs::copy(&self.target, &bak)
+                .with_context(|| format!("cannot back up `{}`", self.target.display()))?;
+        }
+        // The output replaces the target, and so must its permissions
+        if let Ok(metadata) = std::fs::metadata(&self.target) {
+            std::fs::set_permissions(&self.tmp, metadata.permissions()).with_context(|| {
+                format!("cannot set the permissions of `{}`", self.tmp.display())
+            })?;
+        }
+        std::fs::rename(&self.tmp, &self.target)
+            .with_context(|| format!("cannot write to `{}`", self.target.display()))?;
+        self.done = true;
+        Ok(())
+    }
+}
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+fn is_same_file(a: &str, b: &str) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// A destination for the processed trees, either a file or the standard output
+pub struct Output {
+    name: String,
+    out: Encoder,
+    staging: Option<Staging>,
+    format: TreeFormat,
+    /// the number of trees written so far
+    trees: usize,
+}
+impl Output {
+    /// Open the output for trees read from `infile`. If `outfile` is not
+    /// specified, write to the standard output, or back to `infile` if
+    /// in-place edition is allowed. Output files are compressed according to
+    /// their extension, and trees are written in `format`.
+    pub fn create(
+        infile: &str,
+        outfile: Option<&str>,
+        policy: &OutputPolicy,
+        format: TreeFormat,
+    ) -> Result<Self> {
+        let outfile = match outfile {
+            Some(outfile) => {
+                if !policy.in_place && outfile != STDIO && is_same_file(infile, outfile) {
+                    bail!(
+                        "refusing to overwrite `{}`; use --in-place to allow it",
+                        outfile
+                    );
+                }
+                outfile
+            }
+            None => {
+                if policy.in_place {
+                    infile
+                } else {
+                    STDIO
+                }
+            }
+        };
+
+        if outfile == STDIO {
+            Ok(Output {
+                name: outfile.to_owned(),
+                out: Encoder::Plain(Box::new(BufWriter::new(std::io::stdout()))),
+                staging: None,
+                format,
+                trees: 0,
+            })
+        } else {
+            let staging = Staging::new(outfile, policy.backup)?;
+            let out = Compression::from_extension(outfile).encoder(Box::new(BufWriter::new(
+                File::create(&staging.tmp)
+                    .with_context(|| format!("cannot create `{}`", staging.tmp.display()))?,
+            )))?;
+            Ok(Output {
+                name: outfile.to_owned(),
+                out,
+                staging: Some(staging),
+                format,
+                trees: 0,
+            })
+        }
+    }
+
+    pub fn write_str(&mut self, s: &str) -> Result<()> {
+        self.out
+            .write_all(s.as_bytes())
+            .with_context(|| format!("cannot write to `{}`", self.name))
+    }
+
+    pub fn write_tree(&mut self, t: &NewickTree, pretty: bool) -> Result<()> {
+        if self.trees == 0 {
+            self.write_str(self.format.header())?;
+        }
+        match self.format {
+            TreeFormat::Newick => {
+                self.write_str(&Newick::to_newick(t, pretty))?;
+                self.write_str("\n")?;
+            }
+            TreeFormat::Nexus => {
+                // the original tree names are not kept, see `nexus::Reader`
+                self.write_str(&nexus::tree_command(t, &format!("tree_{}", self.trees)))?;
+            }
+            TreeFormat::Phyloxml => self.write_str(&phyloxml::phylogeny(t))?,
+            TreeFormat::Json => {
+                self.write_str(&json::to_json(t)?)?;
+                self.write_str("\n")?;
+            }
+        }
+        self.trees += 1;
+        Ok(())
+    }
+
+    /// Flush the output and, if it is a file, move it to its final destination
+    pub fn finish(mut self) -> Result<()> {
+        if self.trees > 0 {
+            self.write_str(self.format.footer())?;
+        }
+        // Ensure that the temporary file is closed before moving it
+        std::mem::replace(&mut self.out, Encoder::Plain(Box::new(std::io::sink())))
+            .finish()
+            .with_context(|| format!("cannot write to `{}`", self.name))?;
+        if let Some(staging) = self.staging.as_mut() {
+            staging.commit()?;
+        }
+        Ok(())
+    }
+}
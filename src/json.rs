@@ -0,0 +1,101 @@
+use anyhow::Result;
+use newick::{Data, Newick, NewickTree};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// A node of a tree as read from JSON
+#[derive(Deserialize)]
+struct Node {
+    name: Option<String>,
+    length: Option<f32>,
+    #[serde(default)]
+    attrs: HashMap<String, String>,
+    #[serde(default)]
+    children: Vec<Node>,
+}
+
+/// The node `n` of `t`, serialized without copying it
+struct NodeRef<'a> {
+    t: &'a NewickTree,
+    n: usize,
+}
+impl Serialize for NodeRef<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let (t, n) = (self.t, self.n);
+        let mut m = s.serialize_map(None)?;
+        if let Some(name) = t.name(n) {
+            m.serialize_entry("name", name)?;
+        }
+        if let Some(length) = t[n].branch() {
+            m.serialize_entry("length", length)?;
+        }
+        m.serialize_entry("attrs", t.attrs(n))?;
+        m.serialize_entry(
+            "children",
+            &t[n]
+                .children()
+                .iter()
+                .map(|&c| NodeRef { t, n: c })
+                .collect::<Vec<_>>(),
+        )?;
+        m.end()
+    }
+}
+
+/// Serialize `t` as a single-line JSON object of nested nodes, each of them
+/// with its `name` and branch `length` if any, its `attrs` and `children`
+pub fn to_json(t: &NewickTree) -> Result<String> {
+    Ok(if t.is_empty() {
+        String::from("null")
+    } else {
+        serde_json::to_string(&NodeRef { t, n: t.root() })?
+    })
+}
+
+fn from_json(root: Node) -> NewickTree {
+    let mut t = NewickTree::new();
+    let mut todo = vec![(None, root)];
+    while let Some((parent, node)) = todo.pop() {
+        let n = t.add_node(
+            parent,
+            Data {
+                name: node.name,
+                attrs: node.attrs,
+            },
+        );
+        if let Some(length) = node.length {
+            t[n].set_branch(length);
+        }
+        // Children are pushed in reverse to be created in order
+        todo.extend(node.children.into_iter().rev().map(|c| (Some(n), c)));
+    }
+    t
+}
+
+/// A reader for a stream of whitespace-separated JSON trees
+pub struct Reader {
+    trees: serde_json::StreamDeserializer<
+        'static,
+        serde_json::de::IoRead<Box<dyn BufRead>>,
+        Option<Node>,
+    >,
+}
+impl Reader {
+    pub fn new(src: Box<dyn BufRead>) -> Self {
+        let mut de = serde_json::Deserializer::from_reader(src);
+        // Gene trees can be much deeper than the default limit
+        de.disable_recursion_limit();
+        Reader {
+            trees: de.into_iter(),
+        }
+    }
+
+    pub fn next_tree(&mut self) -> Result<Option<NewickTree>> {
+        match self.trees.next() {
+            Some(root) => Ok(Some(root?.map(from_json).unwrap_or_else(NewickTree::new))),
+            None => Ok(None),
+        }
+    }
+}
@@ -1,4 +1,10 @@
 mod actions;
+mod errors;
+mod render;
+mod species;
 mod utils;
 
 pub use actions::*;
+pub use errors::*;
+pub use render::*;
+pub use species::*;
@@ -1,25 +1,60 @@
-use clap::{Parser, Subcommand};
-use newick::*;
-use std::fs::File;
+use clap::{ArgGroup, Parser, Subcommand};
+use newick::{Newick, NewickTree};
+use rayon::prelude::*;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 
 use anyhow::{anyhow, Context, Result};
 
 use syntesuite::genebook::GeneBook;
 mod actions;
+mod errors;
+mod io;
+mod json;
+mod nexus;
+mod phyloxml;
+mod render;
+mod species;
 mod utils;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// sets the input file
-    #[clap(value_parser)]
+    /// sets the input file; use `-` or leave empty to read from the standard input
+    #[clap(value_parser, default_value = io::STDIO)]
     infile: String,
 
-    /// output file name
+    /// output file name; use `-` to write to the standard output, which is
+    /// the default
     #[clap(value_parser, short = 'o', long = "out", global = true)]
     outfile: Option<String>,
 
+    /// if set, allow overwriting the input file; it is the default output
+    /// when none is specified
+    #[clap(value_parser, long, global = true)]
+    in_place: bool,
+
+    /// if set, keep a `.bak` copy of the overwritten output file
+    #[clap(value_parser, long, global = true)]
+    backup: bool,
+
+    /// what to do with trees that can not be processed
+    #[clap(value_enum, long, global = true, default_value = "fail")]
+    on_error: errors::OnError,
+
+    /// the format of the input trees
+    #[clap(value_enum, long, global = true, default_value = "newick")]
+    from: io::TreeFormat,
+
+    /// the format of the output trees; NEXUS trees are written as rooted and
+    /// named `tree_<n>`, regardless of their original names
+    #[clap(value_enum, long, global = true, default_value = "newick")]
+    to: io::TreeFormat,
+
+    /// the number of threads to use; 0 to use all the available cores
+    #[clap(value_parser, long, global = true, default_value_t = 1)]
+    threads: usize,
+
     #[clap(subcommand)]
     command: Command,
 }
@@ -31,6 +66,64 @@ enum Command {
         /// the species tree to use
         #[clap(value_parser, short = 'S', long)]
         species_tree: String,
+
+        /// if set, reconcile the tree by adding the implied speciations and the loss leaves
+        #[clap(value_parser, long)]
+        reconcile: bool,
+
+        /// how to decide whether a node is a duplication
+        #[clap(value_enum, long, default_value = "lca")]
+        method: actions::DuplicationMethod,
+
+        /// with the overlap method, the minimal species Jaccard index between children for a duplication
+        #[clap(value_parser, long, default_value_t = 0.)]
+        min_overlap: f32,
+
+        /// if set, only consider multifurcations as duplications if their children share species
+        #[clap(value_parser, long)]
+        soft_polytomies: bool,
+    },
+
+    /// reroot the trees
+    #[clap(group(ArgGroup::new("method").required(true).args(&["reconcile", "midpoint", "outgroup", "node"])))]
+    Reroot {
+        /// reroot on the branch minimizing the duplication/loss cost against the species tree
+        #[clap(value_parser, long, requires = "species-tree")]
+        reconcile: bool,
+
+        /// reroot at the middle of the longest path between two leaves
+        #[clap(value_parser, long)]
+        midpoint: bool,
+
+        /// reroot above the MRCA of these leaves
+        #[clap(value_parser, long, multiple_values = true)]
+        outgroup: Vec<String>,
+
+        /// reroot above the node with this name
+        #[clap(value_parser, long)]
+        node: Option<String>,
+
+        /// the species tree to use
+        #[clap(value_parser, short = 'S', long)]
+        species_tree: Option<String>,
+    },
+
+    /// list the orthology/paralogy relationships between the genes of annotated trees as TSV
+    Orthologs,
+
+    /// split annotated trees into the orthogroups of an ancestral taxon
+    SplitOrthogroups {
+        /// the species tree to use
+        #[clap(value_parser, short = 'S', long)]
+        species_tree: String,
+
+        /// the ancestral taxon of the species tree defining the orthogroups
+        #[clap(value_parser, short, long)]
+        level: String,
+
+        /// how to write the orthogroups
+        #[clap(value_enum, long, default_value = "newick")]
+        format: actions::OrthogroupFormat,
     },
 
     /// annotate leaves in a tree with their species
@@ -52,7 +145,7 @@ enum Command {
         species: String,
     },
 
-    ///
+    /// annotate nodes with the species matching their taxon ID (`T`)
     Taxonize {
         #[clap(value_parser)]
         mapping: String,
@@ -61,8 +154,79 @@ enum Command {
     /// compress root nodes with a single child
     Compress,
 
+    /// keep only the given leaves, then remove the inner nodes left with a single child
+    #[clap(group(ArgGroup::new("keep").required(true).multiple(true).args(&["leaves", "from-file"])))]
+    Restrict {
+        /// the leaves to keep
+        #[clap(value_parser)]
+        leaves: Vec<String>,
+
+        /// a file listing the leaves to keep, one per line
+        #[clap(value_parser, short, long)]
+        from_file: Option<String>,
+    },
+
     /// convert a newick-formatted tree to a phyl-formatted tree
-    ToPhy,
+    ToPhy {
+        /// if set, add the branch lengths
+        #[clap(value_parser, long)]
+        lengths: bool,
+
+        /// the NHX attributes to add, e.g. `S,D`
+        #[clap(value_parser, short, long, value_delimiter = ',')]
+        attrs: Vec<String>,
+
+        /// the name to give to unnamed nodes
+        #[clap(value_parser, long, default_value = "UNKNOWN")]
+        unnamed: String,
+
+        /// if set, suffix the name of unnamed nodes with their pre-order index
+        #[clap(value_parser, long)]
+        ids: bool,
+    },
+
+    /// convert a tree to a GraphViz graph, highlighting duplications and losses
+    ToDot,
+
+    /// draw the trees, marking duplications and coloring leaves by species
+    #[clap(group(ArgGroup::new("backend").required(true).args(&["svg"])))]
+    Render {
+        /// draw the trees as SVG
+        #[clap(value_parser, long)]
+        svg: bool,
+
+        /// how to lay the trees out
+        #[clap(value_enum, long, default_value = "rectangular")]
+        layout: render::Layout,
+
+        /// if set, scale branches by their lengths
+        #[clap(value_parser, long)]
+        lengths: bool,
+
+        /// the attribute used to color the leaves
+        #[clap(value_parser, long, default_value = "S")]
+        color_by: String,
+    },
+
+    /// convert a tree to PhyloXML, mapping species, duplications and losses to their PhyloXML equivalents
+    ToPhyloxml,
+
+    /// convert a tree to JSON, one nested object per tree
+    ToJson,
+
+    /// convert trees from JSON, as written by `to-json`, to the output format
+    FromJson,
+
+    /// convert a phyl-formatted tree, as written by `to-phy`, to the output format
+    FromPhy {
+        /// the name given to unnamed nodes
+        #[clap(value_parser, long, default_value = "UNKNOWN")]
+        unnamed: String,
+
+        /// if set, unnamed nodes are suffixed by their index, as written by `to-phy --ids`
+        #[clap(value_parser, long)]
+        ids: bool,
+    },
 
     /// list the named leaves of the given tree
     Leaves,
@@ -78,6 +242,22 @@ enum Command {
         /// nodes to recursively remove
         #[clap(value_parser)]
         remove: Vec<String>,
+
+        /// a file listing the nodes to remove, one per line
+        #[clap(value_parser, short, long)]
+        from_file: Option<String>,
+
+        /// remove the nodes whose name matches this regular expression
+        #[clap(value_parser, short, long)]
+        regex: Option<regex::Regex>,
+
+        /// remove the nodes with the given NHX attribute value, e.g. `S=Mus.musculus`
+        #[clap(value_parser = actions::parse_attr_predicate, short, long = "attr")]
+        attrs: Vec<(String, String)>,
+
+        /// if set, list the nodes that would be removed instead of removing them
+        #[clap(value_parser, long)]
+        dry_run: bool,
     },
 
     /// ensure that the provided tree only contains binary speciations
@@ -119,42 +299,295 @@ enum Command {
 
         #[clap(long, help = "if set, show inner nodes")]
         inners: bool,
+
+        /// the node labels, where `{name}`, `{length}` and `{<attribute>}` are replaced by their values, e.g. `{name} [{S}]`
+        #[clap(value_parser, long, default_value = "{name}")]
+        template: String,
+
+        /// when to color duplications and species
+        #[clap(value_enum, long, default_value = "auto")]
+        color: render::ColorMode,
+
+        /// if set, draw the tree with plain ASCII characters
+        #[clap(value_parser, long)]
+        ascii: bool,
+
+        /// the maximal width of the lines
+        #[clap(value_parser, short, long)]
+        width: Option<usize>,
+
+        /// only display the subtree rooted in the node with this name
+        #[clap(value_parser, long)]
+        root: Option<String>,
+
+        /// the number of levels to display, deeper clades being summarized by their number of leaves
+        #[clap(value_parser, short, long)]
+        depth: Option<usize>,
+
+        /// if set, summarize the clades whose leaves all share the same species in a single line
+        #[clap(value_parser, long)]
+        fold: bool,
     },
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let mut trees: Vec<NewickTree> = newick::from_filename(&args.infile)
-        .with_context(|| format!("failed to parse {}", &args.infile))?;
-
-    match args.command {
-        Command::Annotate { species_tree } => {
-            let mut out = String::new();
-            let species_tree = newick::one_from_filename(&species_tree)
-                .context(format!("while parsing {}", &species_tree))?;
-            for t in trees.iter_mut() {
-                actions::annotate_mrcas(t, &species_tree)?;
-                actions::annotate_duplications(t, &species_tree, true);
-                out.push_str(&Newick::to_newick(t, false));
-                out.push('\n');
+/// Feed the trees read from `trees` to `f` by batches of one tree per thread,
+/// along with the index of the first tree of each batch
+fn for_each_batch<F>(trees: io::Trees, mut f: F) -> Result<()>
+where
+    F: FnMut(usize, &mut [NewickTree]) -> Result<()>,
+{
+    let size = rayon::current_num_threads();
+    let mut batch = Vec::with_capacity(size);
+    let mut first = 0;
+    for t in trees {
+        batch.push(t?);
+        if batch.len() == size {
+            f(first, &mut batch)?;
+            first += batch.len();
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        f(first, &mut batch)?;
+    }
+    Ok(())
+}
+
+/// Apply `f` in parallel to a batch of `trees` starting at the `first`-th
+/// one, handling its failures according to `on_error`. Returns, in the input
+/// order, whether each tree should be written out and the result of `f` if
+/// it succeeded.
+fn par_apply<T, F>(
+    first: usize,
+    trees: &mut [NewickTree],
+    on_error: errors::OnError,
+    f: &F,
+) -> Result<Vec<(bool, Option<T>)>>
+where
+    T: Send,
+    F: Fn(&mut NewickTree) -> Result<T, errors::AnnotationError> + Sync,
+{
+    let results = trees
+        .par_iter_mut()
+        .enumerate()
+        .map(|(i, t)| {
+            let mut r = None;
+            on_error
+                .apply(first + i, t, |t| {
+                    r = Some(f(t)?);
+                    Ok(())
+                })
+                .map(|keep| (keep, r))
+        })
+        .collect::<Vec<_>>();
+    // Report the first failing tree, whatever the order of completion
+    Ok(results.into_iter().collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Apply `f` in parallel to all the `trees`, handling its failures according
+/// to `on_error`, then write the resulting trees out in the input order
+fn process<F>(trees: io::Trees, on_error: errors::OnError, out: &mut io::Output, f: F) -> Result<()>
+where
+    F: Fn(&mut NewickTree) -> Result<(), errors::AnnotationError> + Sync,
+{
+    for_each_batch(trees, |first, batch| {
+        let results = par_apply(first, batch, on_error, &f)?;
+        for (t, (keep, _)) in batch.iter().zip(results) {
+            if keep {
+                out.write_tree(t, false)?;
             }
+        }
+        Ok(())
+    })
+}
 
-            File::create(&args.infile)?
-                .write_all(out.as_bytes())
-                .context(format!("Cannot write to `{}`", &args.infile))
+/// Apply the infallible transformation `f` in parallel to all the `trees`,
+/// then write them out in the input order
+fn par_transform<F>(trees: io::Trees, out: &mut io::Output, f: F) -> Result<()>
+where
+    F: Fn(&mut NewickTree) + Sync,
+{
+    for_each_batch(trees, |_, batch| {
+        batch.par_iter_mut().for_each(&f);
+        for t in batch.iter() {
+            out.write_tree(t, false)?;
         }
-        Command::Compress => {
-            let mut out = String::new();
-            for t in trees.iter_mut() {
-                actions::compress(t).map(|_| {
-                    out.push_str(&Newick::to_newick(t, false));
-                    out.push('\n');
+        Ok(())
+    })
+}
+
+/// The file a conversion of `infile` should be written to: `outfile` if it
+/// is set, otherwise `infile` with the `extension` of the target format
+fn converted_filename(infile: &str, outfile: Option<&str>, extension: &str) -> Result<String> {
+    Ok(if let Some(outfile) = outfile {
+        outfile.to_owned()
+    } else if infile == io::STDIO {
+        io::STDIO.to_owned()
+    } else {
+        std::path::Path::new(infile)
+            .with_extension(extension)
+            .to_str()
+            .with_context(|| anyhow!("invalid filename found"))?
+            .to_owned()
+    })
+}
+
+fn main() -> Result<()> {
+    let Args {
+        infile,
+        outfile,
+        in_place,
+        backup,
+        on_error,
+        from,
+        to,
+        threads,
+        command,
+    } = Args::parse();
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .context("failed to start the thread pool")?;
+    let trees = match command {
+        Command::FromJson => io::read_trees(&infile, io::TreeFormat::Json)?,
+        Command::FromPhy { ref unnamed, ids } => io::read_phy_trees(&infile, unnamed, ids)?,
+        _ => io::read_trees(&infile, from)?,
+    };
+    let policy = io::OutputPolicy { in_place, backup };
+    let output = || io::Output::create(&infile, outfile.as_deref(), &policy, to);
+
+    match command {
+        Command::Annotate {
+            species_tree,
+            reconcile,
+            method,
+            min_overlap,
+            soft_polytomies,
+        } => {
+            let species_tree = species::SpeciesTree::new(io::read_tree(&species_tree)?);
+            let mut out = output()?;
+            process(trees, on_error, &mut out, |t| {
+                actions::annotate_mrcas(t, &species_tree)?;
+                actions::annotate_duplications(
+                    t,
+                    &species_tree,
+                    true,
+                    method,
+                    min_overlap,
+                    soft_polytomies,
+                )?;
+                if reconcile {
+                    actions::reconcile(t, &species_tree, true)?;
+                }
+                Ok(())
+            })?;
+            out.finish()
+        }
+        Command::Reroot {
+            reconcile,
+            midpoint,
+            outgroup,
+            node,
+            species_tree,
+        } => {
+            let species_tree = species_tree
+                .map(|species_tree| io::read_tree(&species_tree).map(species::SpeciesTree::new))
+                .transpose()?;
+            let mut out = output()?;
+            process(trees, on_error, &mut out, |t| {
+                if reconcile {
+                    actions::reroot_reconcile(t, species_tree.as_ref().unwrap())?;
+                } else if midpoint {
+                    actions::reroot_midpoint(t);
+                } else if let Some(node) = node.as_ref() {
+                    actions::reroot_node(t, node)?;
+                } else {
+                    actions::reroot_outgroup(t, &outgroup)?;
+                }
+                Ok(())
+            })?;
+            out.finish()
+        }
+        Command::Orthologs => {
+            let mut out = output()?;
+            out.write_str("gene_a\tgene_b\tspecies_a\tspecies_b\trelation\tlca_taxon\n")?;
+            for_each_batch(trees, |first, batch| {
+                let results = par_apply(first, batch, on_error, &|t: &mut NewickTree| {
+                    actions::orthologs(t)
+                })?;
+                for rows in results.into_iter().filter_map(|(_, rows)| rows) {
+                    out.write_str(&rows)?;
+                }
+                Ok(())
+            })?;
+            out.finish()
+        }
+        Command::SplitOrthogroups {
+            species_tree,
+            level,
+            format,
+        } => {
+            let species_tree = species::SpeciesTree::new(io::read_tree(&species_tree)?);
+            let mut out = output()?;
+            let mut i = 0;
+            for_each_batch(trees, |first, batch| {
+                let results = par_apply(first, batch, on_error, &|t: &mut NewickTree| {
+                    actions::split_orthogroups(t, &species_tree, &level)
                 })?;
+                for ogs in results.into_iter().filter_map(|(_, ogs)| ogs) {
+                    for og in ogs {
+                        match format {
+                            actions::OrthogroupFormat::Newick => out.write_tree(&og, false)?,
+                            actions::OrthogroupFormat::Tsv => {
+                                for l in og.leaves() {
+                                    if let Some(name) = og.name(l) {
+                                        if !actions::is_loss(&og, l) {
+                                            out.write_str(&format!("OG{}\t{}\n", i, name))?;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        i += 1;
+                    }
+                }
+                Ok(())
+            })?;
+            out.finish()
+        }
+        Command::Compress => {
+            let mut out = output()?;
+            par_transform(trees, &mut out, actions::compress)?;
+            out.finish()
+        }
+        Command::Restrict { leaves, from_file } => {
+            let mut keep = leaves.into_iter().collect::<std::collections::HashSet<_>>();
+            if let Some(from_file) = from_file {
+                keep.extend(io::read_names(&from_file)?);
             }
-            let outfile = args.outfile.unwrap_or(args.infile);
-            File::create(&outfile)?
-                .write_all(out.as_bytes())
-                .with_context(|| anyhow!("cannot write to `{}`", &outfile))
+            let found = std::sync::Mutex::new(std::collections::HashSet::new());
+            let mut out = output()?;
+            process(trees, on_error, &mut out, |t| {
+                found
+                    .lock()
+                    .unwrap()
+                    .extend(t.leaf_names().filter(|name| keep.contains(*name)).cloned());
+                actions::restrict(t, &keep)
+            })?;
+            let found = found.into_inner().unwrap();
+            let mut missing = keep
+                .iter()
+                .filter(|name| !found.contains(*name))
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                missing.sort_unstable();
+                eprintln!(
+                    "leaves to keep not found in any tree: {}",
+                    missing.join(", ")
+                );
+            }
+            out.finish()
         }
         Command::Speciesize {
             database,
@@ -162,201 +595,242 @@ fn main() -> Result<()> {
             id,
             species: _species,
         } => {
-            let mut out = String::new();
-            let mut book = if cache_db {
+            let book = if cache_db {
                 GeneBook::in_memory(&database, 0, &id)
             } else {
                 GeneBook::inline(&database, 0, &id)
             }?;
 
-            for t in trees.iter_mut() {
-                actions::speciesize(t, &mut book).map(|_| {
-                    out.push_str(&Newick::to_newick(t, false));
-                    out.push('\n');
-                })?
-            }
-
-            let outfile = args.outfile.unwrap_or(args.infile);
-            File::create(&outfile)?
-                .write_all(out.as_bytes())
-                .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-            Ok(())
+            let mut out = output()?;
+            process(trees, on_error, &mut out, |t| actions::speciesize(t, &book))?;
+            out.finish()
         }
         Command::Taxonize { mapping } => {
-            let mut out = String::new();
-
-            for t in trees.iter_mut() {
-                actions::taxonize(t, &mapping).map(|_| {
-                    out.push_str(&Newick::to_newick(t, false));
-                    out.push('\n');
-                })?
+            let mapping = actions::taxon_mapping(io::open(&mapping)?);
+            let mut out = output()?;
+            par_transform(trees, &mut out, |t| actions::taxonize(t, &mapping))?;
+            out.finish()
+        }
+        Command::ToPhy {
+            lengths,
+            attrs,
+            unnamed,
+            ids,
+        } => {
+            let options = actions::PhyOptions {
+                lengths,
+                attrs,
+                placeholder: unnamed,
+                ids,
+            };
+            let outfile = converted_filename(&infile, outfile.as_deref(), "phy")?;
+            let mut out = io::Output::create(&infile, Some(&outfile), &policy, to)?;
+
+            for_each_batch(trees, |_, batch| {
+                let phys = batch
+                    .par_iter()
+                    .map(|t| actions::to_phy(t, &options))
+                    .collect::<Result<Vec<_>>>()?;
+                for phy in phys {
+                    out.write_str(&phy)?;
+                    out.write_str("\n")?;
+                }
+                Ok(())
+            })?;
+            out.finish()
+        }
+        Command::ToDot => {
+            let outfile = converted_filename(&infile, outfile.as_deref(), "dot")?;
+            let mut out = io::Output::create(&infile, Some(&outfile), &policy, to)?;
+            for_each_batch(trees, |first, batch| {
+                let dots = batch
+                    .par_iter()
+                    .enumerate()
+                    .map(|(i, t)| actions::to_dot(t, &format!("tree_{}", first + i)))
+                    .collect::<Vec<_>>();
+                for dot in dots {
+                    out.write_str(&dot)?;
+                }
+                Ok(())
+            })?;
+            out.finish()
+        }
+        Command::Render {
+            svg: _,
+            layout,
+            lengths,
+            color_by,
+        } => {
+            let outfile = converted_filename(&infile, outfile.as_deref(), "svg")?;
+            let mut out = io::Output::create(&infile, Some(&outfile), &policy, to)?;
+            let mut drawings = Vec::new();
+            for_each_batch(trees, |_, batch| {
+                drawings.par_extend(
+                    batch
+                        .par_iter()
+                        .map(|t| render::render_svg(t, layout, lengths, &color_by)),
+                );
+                Ok(())
+            })?;
+            out.write_str(&render::svg_document(&drawings))?;
+            out.finish()
+        }
+        Command::ToPhyloxml => {
+            let outfile = converted_filename(&infile, outfile.as_deref(), "xml")?;
+            let mut out =
+                io::Output::create(&infile, Some(&outfile), &policy, io::TreeFormat::Phyloxml)?;
+            for t in trees {
+                out.write_tree(&t?, false)?;
             }
-
-            let outfile = args.outfile.unwrap_or(args.infile);
-            File::create(&outfile)?
-                .write_all(out.as_bytes())
-                .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-            Ok(())
+            out.finish()
         }
-        Command::ToPhy {} => {
-            let outfile = args.outfile.unwrap_or(
-                std::path::Path::new(&args.infile)
-                    .with_extension("phy")
-                    .to_str()
-                    .with_context(|| anyhow!("invalid filename found"))?
-                    .to_owned(),
-            );
-            let mut out = File::create(&outfile)?;
-
-            for t in trees.iter() {
-                out.write_all(actions::to_phy(t)?.as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-                out.write_all("\n".as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
+        Command::ToJson => {
+            let outfile = converted_filename(&infile, outfile.as_deref(), "json")?;
+            let mut out =
+                io::Output::create(&infile, Some(&outfile), &policy, io::TreeFormat::Json)?;
+            for t in trees {
+                out.write_tree(&t?, false)?;
             }
-
-            Ok(())
+            out.finish()
         }
-        Command::Leaves {} => {
-            for t in trees.iter() {
+        Command::FromJson | Command::FromPhy { .. } => {
+            let mut out = output()?;
+            for t in trees {
+                out.write_tree(&t?, false)?;
+            }
+            out.finish()
+        }
+        Command::Leaves => {
+            for t in trees {
+                let t = t?;
                 t.leaves()
                     .filter_map(|l| t.name(l))
                     .for_each(|n| println!("{}", n));
             }
             Ok(())
         }
-        Command::Nodes {} => {
-            for t in trees.iter() {
+        Command::Nodes => {
+            for t in trees {
+                let t = t?;
                 t.nodes()
                     .filter_map(|n| t.name(n))
                     .for_each(|n| println!("{}", n));
             }
             Ok(())
         }
-        Command::Normalize {} => {
-            let outfile = args.outfile.unwrap_or(args.infile);
-            let mut out = File::create(&outfile)?;
-
-            for t in trees.iter_mut() {
-                actions::normalize(t);
-                out.write_all(Newick::to_newick(t, false).as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-                out.write_all("\n".as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-            }
-            Ok(())
+        Command::Normalize => {
+            let mut out = output()?;
+            par_transform(trees, &mut out, actions::normalize)?;
+            out.finish()
         }
-        Command::Prune { remove } => {
-            let outfile = args.outfile.unwrap_or(args.infile);
-            let mut out = File::create(&outfile)?;
-
-            for mut t in trees {
-                t.delete_nodes(
-                    &t.nodes()
-                        .filter(|&n| t.name(n).map(|s| remove.contains(s)).unwrap_or(false))
-                        .collect::<Vec<_>>(),
-                );
-                t.prune(|n| n.name.is_none());
-                out.write_all(Newick::to_newick(&t, false).as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-                out.write_all("\n".as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
+        Command::Prune {
+            remove,
+            from_file,
+            regex,
+            attrs,
+            dry_run,
+        } => {
+            let mut names = remove.into_iter().collect::<std::collections::HashSet<_>>();
+            if let Some(from_file) = from_file {
+                names.extend(io::read_names(&from_file)?);
+            }
+            let selector = actions::PruneSelector {
+                names,
+                regex,
+                attrs,
+            };
+
+            if dry_run {
+                // The trees are left untouched, even with --in-place
+                for t in trees {
+                    let t = t?;
+                    for n in selector.select(&t) {
+                        println!("{}", errors::node_name(&t, n));
+                    }
+                }
+                Ok(())
+            } else {
+                let mut out = output()?;
+                par_transform(trees, &mut out, |t| actions::prune(t, &selector))?;
+                out.finish()
             }
-            Ok(())
         }
         Command::Binarize {} => {
-            let outfile = args.outfile.unwrap_or(args.infile);
-            let mut out = File::create(&outfile)?;
-
-            for t in trees.iter_mut() {
-                actions::binarize(t);
-                out.write_all(Newick::to_newick(t, false).as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-                out.write_all("\n".as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-            }
-            Ok(())
+            let mut out = output()?;
+            par_transform(trees, &mut out, actions::binarize)?;
+            out.finish()
         }
         Command::Rename {
             mapping_file,
             separator,
         } => {
-            let mapping = std::io::BufReader::new(
-                File::open(&mapping_file)
-                    .with_context(|| anyhow!("while opening `{}`", &mapping_file))?,
-            )
-            .lines()
-            .filter_map(|l| {
-                l.ok().and_then(|l| {
-                    let (src, tgt) = if let Some(sep) = separator.as_ref() {
-                        let mut s = l.split(sep);
-                        (s.next()?.to_owned(), s.next()?.to_owned())
-                    } else {
-                        let mut s = l.split_whitespace();
-                        (s.next()?.to_owned(), s.next()?.to_owned())
-                    };
-                    Some((src, tgt))
+            let mapping = io::open(&mapping_file)?
+                .lines()
+                .filter_map(|l| {
+                    l.ok().and_then(|l| {
+                        let (src, tgt) = if let Some(sep) = separator.as_ref() {
+                            let mut s = l.split(sep);
+                            (s.next()?.to_owned(), s.next()?.to_owned())
+                        } else {
+                            let mut s = l.split_whitespace();
+                            (s.next()?.to_owned(), s.next()?.to_owned())
+                        };
+                        Some((src, tgt))
+                    })
                 })
-            })
-            .collect::<std::collections::HashMap<String, String>>();
-            let outfile = args.outfile.unwrap_or(args.infile);
-            let mut out = File::create(&outfile)?;
-
-            for t in trees.iter_mut() {
-                actions::rename(t, &mapping);
-                out.write_all(Newick::to_newick(t, false).as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-                out.write_all("\n".as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-            }
-            Ok(())
+                .collect::<std::collections::HashMap<String, String>>();
+
+            let mut out = output()?;
+            par_transform(trees, &mut out, |t| actions::rename(t, &mapping))?;
+            out.finish()
         }
         Command::Format => {
-            let outfile = args.outfile.unwrap_or(args.infile);
-            let mut out = File::create(&outfile)?;
-
+            let mut out = output()?;
             for t in trees {
-                out.write_all(Newick::to_newick(&t, true).as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
+                out.write_tree(&t?, true)?;
             }
-            Ok(())
+            out.finish()
         }
         Command::Strip { to_strip } => {
-            println!("Stripping {:?}", to_strip);
+            eprintln!("Stripping {:?}", to_strip);
 
-            let outfile = args.outfile.unwrap_or(args.infile);
-            let mut out = File::create(&outfile)?;
-
-            for t in trees.iter_mut() {
-                actions::strip(t, &to_strip);
-                out.write_all(Newick::to_newick(t, false).as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-                out.write_all("\n".as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-            }
-            Ok(())
+            let mut out = output()?;
+            par_transform(trees, &mut out, |t| actions::strip(t, &to_strip))?;
+            out.finish()
         }
         Command::Sort { leaves } => {
-            let outfile = args.outfile.unwrap_or(args.infile);
-            let mut out = File::create(&outfile)?;
-
-            for t in trees.iter_mut() {
-                actions::sort(t, leaves);
-                out.write_all(Newick::to_newick(t, false).as_bytes())
-                    .with_context(|| anyhow!("cannot write to `{}`", &outfile))?;
-            }
-            Ok(())
+            let mut out = output()?;
+            par_transform(trees, &mut out, |t| actions::sort(t, leaves))?;
+            out.finish()
         }
-        Command::Show { lengths, inners } => {
-            for t in trees.iter_mut() {
-                println!(
-                    "{}",
-                    t.to_string(
-                        |n| n.name.as_ref().map(|n| n.to_owned()).unwrap_or_default(),
-                        |e| if lengths { e.to_string() } else { "".into() },
-                        !inners,
-                    )
-                );
+        Command::Show {
+            lengths,
+            inners,
+            template,
+            color,
+            ascii,
+            width,
+            root,
+            depth,
+            fold,
+        } => {
+            let options = render::TextOptions {
+                template,
+                inners,
+                lengths,
+                ascii,
+                colors: match color {
+                    render::ColorMode::Auto => std::io::stdout().is_terminal(),
+                    render::ColorMode::Always => true,
+                    render::ColorMode::Never => false,
+                },
+                width,
+                root,
+                depth,
+                fold,
+            };
+            for t in trees {
+                println!("{}", render::render_text(&t?, &options)?);
             }
             Ok(())
         }
@@ -0,0 +1,290 @@
+use anyhow::{bail, Context, Result};
+use newick::{Newick, NewickTree};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// The characters that can not appear in an unquoted NEXUS token
+const PUNCTUATION: &str = "()[]{}/\\,;:=*'\"`+-<>";
+
+/// Convert a NEXUS token, possibly quoted, to a name suitable for a Newick
+/// tree
+fn unquote(token: &str) -> String {
+    let token = token.trim();
+    let unquoted = if token.len() >= 2 && token.starts_with('\'') && token.ends_with('\'') {
+        token[1..token.len() - 1].replace("''", "'")
+    } else {
+        token.to_owned()
+    };
+    unquoted
+        .chars()
+        .map(|c| {
+            if c.is_whitespace() || ":,;()[]".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Quote `name` if it contains characters that NEXUS would interpret
+fn quote(name: &str) -> String {
+    if name
+        .chars()
+        .any(|c| c.is_whitespace() || PUNCTUATION.contains(c))
+    {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Remove the comments heading `s`
+fn strip_leading_comments(mut s: &str) -> &str {
+    while s.starts_with('[') {
+        let mut depth = 0;
+        let mut end = s.len();
+        for (i, c) in s.char_indices() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                end = i + 1;
+                break;
+            }
+        }
+        s = s[end..].trim_start();
+    }
+    s
+}
+
+/// Split `s` on the top-level occurrences of `sep`, i.e. those outside of
+/// quotes and comments
+fn split_top_level(s: &str, sep: char) -> Vec<&str> {
+    let mut r = Vec::new();
+    let mut in_quote = false;
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' if depth == 0 => in_quote = !in_quote,
+            '[' if !in_quote => depth += 1,
+            ']' if !in_quote && depth > 0 => depth -= 1,
+            _ if c == sep && !in_quote && depth == 0 => {
+                r.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    r.push(&s[start..]);
+    r
+}
+
+/// Rewrite the tree description of a NEXUS `TREE` command as a Newick string,
+/// unquoting labels and dropping all comments but NHX annotations
+fn to_newick_string(description: &str) -> Result<String> {
+    let mut r = String::with_capacity(description.len() + 1);
+    let mut chars = description.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let mut label = String::from("'");
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            label.push_str("''");
+                        }
+                        Some('\'') => break,
+                        Some(c) => label.push(c),
+                        None => bail!("unterminated quoted label"),
+                    }
+                }
+                label.push('\'');
+                r.push_str(&unquote(&label));
+            }
+            '[' => {
+                let mut comment = String::from("[");
+                let mut depth = 1;
+                while depth > 0 {
+                    match chars.next() {
+                        Some(c) => {
+                            match c {
+                                '[' => depth += 1,
+                                ']' => depth -= 1,
+                                _ => {}
+                            }
+                            comment.push(c);
+                        }
+                        None => bail!("unterminated comment"),
+                    }
+                }
+                if comment.starts_with("[&&NHX") {
+                    r.push_str(&comment);
+                }
+            }
+            c if c.is_whitespace() => {}
+            c => r.push(c),
+        }
+    }
+    r.push(';');
+    Ok(r)
+}
+
+/// A reader for the trees of the `TREES` blocks of a NEXUS file. Only the
+/// trees themselves are read: their names and their `[&R]`/`[&U]` rooting
+/// comments are discarded.
+pub struct Reader {
+    src: Box<dyn BufRead>,
+    buffer: Vec<u8>,
+    in_trees: bool,
+    /// the `TRANSLATE` table of the current `TREES` block
+    translate: HashMap<String, String>,
+}
+impl Reader {
+    pub fn new(src: Box<dyn BufRead>) -> Self {
+        Reader {
+            src,
+            buffer: Vec::new(),
+            in_trees: false,
+            translate: HashMap::new(),
+        }
+    }
+
+    /// Read the next command, i.e. everything up to the next `;` that is
+    /// neither quoted nor in a comment
+    fn next_command(&mut self) -> Result<Option<String>> {
+        self.buffer.clear();
+        let mut in_quote = false;
+        let mut depth = 0;
+        let mut scanned = 0;
+        loop {
+            if self.src.read_until(b';', &mut self.buffer)? == 0 {
+                if String::from_utf8_lossy(&self.buffer).trim().is_empty() {
+                    return Ok(None);
+                } else {
+                    bail!("unterminated NEXUS command");
+                }
+            }
+            for &b in &self.buffer[scanned..] {
+                match b {
+                    b'\'' if depth == 0 => in_quote = !in_quote,
+                    b'[' if !in_quote => depth += 1,
+                    b']' if !in_quote && depth > 0 => depth -= 1,
+                    _ => {}
+                }
+            }
+            scanned = self.buffer.len();
+            if !in_quote && depth == 0 {
+                let command = std::str::from_utf8(&self.buffer[..self.buffer.len() - 1])?;
+                return Ok(Some(command.trim().to_owned()));
+            }
+        }
+    }
+
+    fn parse_translate(&mut self, table: &str) {
+        self.translate = split_top_level(table, ',')
+            .into_iter()
+            .filter_map(|entry| {
+                let entry = entry.trim();
+                let split = entry.find(char::is_whitespace)?;
+                Some((unquote(&entry[..split]), unquote(&entry[split..])))
+            })
+            .collect();
+    }
+
+    fn parse_tree(&self, command: &str) -> Result<NewickTree> {
+        let parts = split_top_level(command, '=');
+        if parts.len() < 2 {
+            bail!("`=` not found in TREE command");
+        }
+        let name = unquote(parts[0].split('[').next().unwrap_or_default());
+        let description = parts[1..].join("=");
+        let mut t = newick::one_from_string(to_newick_string(&description)?)
+            .with_context(|| format!("while parsing tree `{}`", name))?;
+        let leaves = t.leaves().collect::<Vec<_>>();
+        for l in leaves {
+            if let Some(name) = t.name(l).and_then(|name| self.translate.get(name)) {
+                t[l].data_mut().name = Some(name.to_owned());
+            }
+        }
+        Ok(t)
+    }
+
+    /// Read the next tree, with leaf names resolved through the `TRANSLATE`
+    /// table of its block
+    pub fn next_tree(&mut self) -> Result<Option<NewickTree>> {
+        while let Some(command) = self.next_command()? {
+            let command = strip_leading_comments(command.trim_start());
+            let command = match command.get(..6) {
+                Some(header) if header.eq_ignore_ascii_case("#NEXUS") => {
+                    strip_leading_comments(command[6..].trim_start())
+                }
+                _ => command,
+            };
+            let split = command
+                .find(|c: char| c.is_whitespace())
+                .unwrap_or(command.len());
+            let (keyword, rest) = command.split_at(split);
+            match keyword.to_lowercase().as_str() {
+                "begin" => {
+                    self.in_trees = rest.trim().eq_ignore_ascii_case("trees");
+                    self.translate.clear();
+                }
+                "end" | "endblock" => self.in_trees = false,
+                "translate" if self.in_trees => self.parse_translate(rest),
+                "tree" | "utree" if self.in_trees => {
+                    return self.parse_tree(rest).map(Some);
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The header of a NEXUS file opening its `TREES` block
+pub const HEADER: &str = "#NEXUS\nbegin trees;\n";
+
+/// The footer of a NEXUS file closing its `TREES` block
+pub const FOOTER: &str = "end;\n";
+
+/// Render `t` as a NEXUS `TREE` command named `name`. As the trees read
+/// carry neither name nor rooting, the tree is always marked as rooted.
+pub fn tree_command(t: &NewickTree, name: &str) -> String {
+    fn fmt_node(t: &NewickTree, n: usize, r: &mut String) {
+        if !t[n].is_leaf() {
+            r.push('(');
+            for (i, &c) in t[n].children().iter().enumerate() {
+                if i > 0 {
+                    r.push(',');
+                }
+                fmt_node(t, c, r);
+            }
+            r.push(')');
+        }
+        if let Some(name) = t.name(n) {
+            r.push_str(&quote(name));
+        }
+        if let Some(l) = t[n].branch() {
+            r.push_str(&format!(":{}", l));
+        }
+        if !t.attrs(n).is_empty() {
+            r.push_str("[&&NHX");
+            for (k, v) in t.attrs(n).iter() {
+                r.push_str(&format!(":{}={}", k, v));
+            }
+            r.push(']');
+        }
+    }
+
+    let mut r = format!("\ttree {} = [&R] ", quote(name));
+    if !t.is_empty() {
+        fmt_node(t, t.root(), &mut r);
+    }
+    r.push_str(";\n");
+    r
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use newick::{Data, Newick, NewickTree};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// The opening of a PhyloXML document
+pub const HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<phyloxml xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.phyloxml.org http://www.phyloxml.org/1.10/phyloxml.xsd" xmlns="http://www.phyloxml.org">
+"#;
+
+/// The closing of a PhyloXML document
+pub const FOOTER: &str = "</phyloxml>\n";
+
+/// Render `t` as a PhyloXML `<phylogeny>` element. The species (`S`) and
+/// taxon ID (`T`) are stored in `<taxonomy>`, duplications (`D`) and losses
+/// (`L`) as `<events>`, and all other attributes as `NHX:`-prefixed
+/// `<property>`.
+pub fn phylogeny(t: &NewickTree) -> String {
+    fn fmt_node(t: &NewickTree, n: usize, depth: usize, r: &mut String) {
+        let indent = "  ".repeat(depth + 2);
+        let attrs = t.attrs(n);
+        r.push_str(&format!("{}<clade>\n", indent));
+        if let Some(name) = t.name(n) {
+            r.push_str(&format!("{}  <name>{}</name>\n", indent, escape(name)));
+        }
+        if let Some(l) = t[n].branch() {
+            r.push_str(&format!(
+                "{}  <branch_length>{}</branch_length>\n",
+                indent, l
+            ));
+        }
+        if attrs.contains_key("S") || attrs.contains_key("T") {
+            r.push_str(&format!("{}  <taxonomy>\n", indent));
+            if let Some(taxon) = attrs.get("T") {
+                r.push_str(&format!(
+                    "{}    <id provider=\"ncbi\">{}</id>\n",
+                    indent,
+                    escape(taxon)
+                ));
+            }
+            if let Some(species) = attrs.get("S") {
+                r.push_str(&format!(
+                    "{}    <scientific_name>{}</scientific_name>\n",
+                    indent,
+                    escape(species)
+                ));
+            }
+            r.push_str(&format!("{}  </taxonomy>\n", indent));
+        }
+        let event = match (attrs.get("D").map(String::as_str), attrs.get("L")) {
+            (_, Some(l)) if l == "Y" => Some("losses"),
+            (Some("Y"), _) => Some("duplications"),
+            (Some("N"), _) => Some("speciations"),
+            _ => None,
+        };
+        if let Some(event) = event {
+            r.push_str(&format!(
+                "{}  <events>\n{}    <{}>1</{}>\n{}  </events>\n",
+                indent, indent, event, event, indent
+            ));
+        }
+        let mut properties = attrs
+            .iter()
+            .filter(|(k, _)| !["S", "T", "D", "L"].contains(&k.as_str()))
+            .collect::<Vec<_>>();
+        properties.sort();
+        for (k, v) in properties {
+            let datatype = if v.parse::<f64>().is_ok() {
+                "xsd:decimal"
+            } else {
+                "xsd:string"
+            };
+            r.push_str(&format!(
+                "{}  <property ref=\"NHX:{}\" datatype=\"{}\" applies_to=\"clade\">{}</property>\n",
+                indent,
+                escape(k),
+                datatype,
+                escape(v)
+            ));
+        }
+        for &c in t[n].children() {
+            fmt_node(t, c, depth + 1, r);
+        }
+        r.push_str(&format!("{}</clade>\n", indent));
+    }
+
+    let mut r = String::from("  <phylogeny rooted=\"true\">\n");
+    if !t.is_empty() {
+        fmt_node(t, t.root(), 0, &mut r);
+    }
+    r.push_str("  </phylogeny>\n");
+    r
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Result<Option<String>> {
+    Ok(match e.try_get_attribute(name)? {
+        Some(a) => Some(a.unescape_value()?.into_owned()),
+        None => None,
+    })
+}
+
+/// Add to `tree` a node for the clade opened by `e`
+fn add_clade(
+    tree: Option<&mut NewickTree>,
+    parent: Option<usize>,
+    e: &BytesStart,
+) -> Result<usize> {
+    let t = tree.context("<clade> found outside of <phylogeny>")?;
+    let n = t.add_node(
+        parent,
+        Data {
+            name: None,
+            attrs: HashMap::new(),
+        },
+    );
+    if let Some(l) = attribute(e, "branch_length")? {
+        t[n].set_branch(l.parse()?);
+    }
+    Ok(n)
+}
+
+/// A reader for the `<phylogeny>` elements of a PhyloXML file
+pub struct Reader {
+    xml: quick_xml::Reader<Box<dyn BufRead>>,
+    buffer: Vec<u8>,
+}
+impl Reader {
+    pub fn new(src: Box<dyn BufRead>) -> Self {
+        let mut xml = quick_xml::Reader::from_reader(src);
+        xml.trim_text(true);
+        Reader {
+            xml,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Read the next phylogeny, mapping its annotations back to the NHX
+    /// attributes they have been generated from
+    pub fn next_tree(&mut self) -> Result<Option<NewickTree>> {
+        let mut tree: Option<NewickTree> = None;
+        // the clades being read
+        let mut clades = Vec::new();
+        // the elements opened in the current clade
+        let mut path: Vec<String> = Vec::new();
+        // the `ref` of the property being read
+        let mut property = None;
+
+        loop {
+            self.buffer.clear();
+            match self.xml.read_event_into(&mut self.buffer)? {
+                Event::Start(e) if e.local_name().as_ref() == b"phylogeny" => {
+                    tree = Some(NewickTree::new());
+                    clades.clear();
+                }
+                Event::Start(e) if e.local_name().as_ref() == b"clade" => {
+                    let n = add_clade(tree.as_mut(), clades.last().copied(), &e)?;
+                    clades.push(n);
+                    path.clear();
+                }
+                Event::Empty(e) if e.local_name().as_ref() == b"clade" => {
+                    add_clade(tree.as_mut(), clades.last().copied(), &e)?;
+                }
+                Event::Start(e) => {
+                    if e.local_name().as_ref() == b"property" {
+                        property = attribute(&e, "ref")?;
+                    }
+                    path.push(String::from_utf8_lossy(e.local_name().as_ref()).into_owned());
+                }
+                Event::Text(text) => {
+                    if let (Some(t), Some(&n)) = (tree.as_mut(), clades.last()) {
+                        let text = text.unescape()?;
+                        let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+                        match path.as_slice() {
+                            ["name"] => t[n].data_mut().name = Some(text.into_owned()),
+                            ["branch_length"] => t[n].set_branch(text.parse()?),
+                            ["taxonomy", "scientific_name"] => {
+                                t.attrs_mut(n).insert("S".into(), text.into_owned());
+                            }
+                            ["taxonomy", "id"] => {
+                                t.attrs_mut(n).insert("T".into(), text.into_owned());
+                            }
+                            ["events", event] if text.parse::<usize>().unwrap_or(0) > 0 => {
+                                let attrs = t.attrs_mut(n);
+                                match *event {
+                                    "duplications" => {
+                                        attrs.insert("D".into(), "Y".into());
+                                    }
+                                    "speciations" => {
+                                        attrs.entry("D".into()).or_insert_with(|| "N".into());
+                                    }
+                                    "losses" => {
+                                        attrs.insert("L".into(), "Y".into());
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            ["property"] => {
+                                // NHX keys can not contain `:`
+                                if let Some(key) =
+                                    property.as_ref().and_then(|r| r.rsplit(':').next())
+                                {
+                                    t.attrs_mut(n).insert(key.to_owned(), text.into_owned());
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Event::End(e) => match e.local_name().as_ref() {
+                    b"clade" => {
+                        clades.pop();
+                        path.clear();
+                    }
+                    b"phylogeny" => return Ok(tree),
+                    _ => {
+                        path.pop();
+                    }
+                },
+                Event::Eof => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use newick::{Newick, NewickTree};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use crate::actions::is_loss;
+use crate::utils::postorder;
+
+/// The vertical space allocated to each leaf
+const ROW: f32 = 16.;
+/// The approximate width of a label character
+const CHAR_WIDTH: f32 = 7.;
+/// The space around a drawing
+const MARGIN: f32 = 20.;
+/// The horizontal extent of a rectangular tree, labels excluded
+const WIDTH: f32 = 500.;
+/// The colors given to the successive attribute values
+const PALETTE: [&str; 10] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+    "#bcbd22", "#17becf",
+];
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Layout {
+    Rectangular,
+    Circular,
+}
+
+/// An SVG fragment, to be placed in a document with [`svg_document`]
+pub struct Drawing {
+    pub svg: String,
+    pub width: f32,
+    pub height: f32,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Draw `t` with the given `layout`, scaling the branches by their lengths if
+/// `lengths` is set; duplications are marked by a red square, and leaves are
+/// colored by their `color_by` attribute.
+pub fn render_svg(t: &NewickTree, layout: Layout, lengths: bool, color_by: &str) -> Drawing {
+    if t.is_empty() {
+        return Drawing {
+            svg: String::new(),
+            width: 0.,
+            height: 0.,
+        };
+    }
+
+    // Leaves are laid out in pre-order
+    let mut leaves = Vec::new();
+    // distance from the root, in branch lengths or in levels
+    let mut depth = HashMap::new();
+    let mut todo = vec![(t.root(), 0.)];
+    while let Some((n, d)) = todo.pop() {
+        depth.insert(n, d);
+        if t[n].is_leaf() {
+            leaves.push(n);
+        }
+        for &c in t[n].children().iter().rev() {
+            let l = if lengths {
+                t[c].branch().copied().unwrap_or(0.)
+            } else {
+                1.
+            };
+            todo.push((c, d + l));
+        }
+    }
+    let max_depth = depth.values().copied().fold(0., f32::max).max(f32::EPSILON);
+
+    // the position of the leaves along the y axis or around the circle, from
+    // 0 to 1, then of their ancestors in between their children
+    let mut slot = HashMap::new();
+    for (i, &l) in leaves.iter().enumerate() {
+        slot.insert(l, (i as f32 + 0.5) / leaves.len() as f32);
+    }
+    for n in postorder(t) {
+        let children = t[n].children();
+        if let (Some(first), Some(last)) = (children.first(), children.last()) {
+            slot.insert(n, (slot[first] + slot[last]) / 2.);
+        }
+    }
+
+    let mut colors = HashMap::new();
+    for &l in &leaves {
+        if let Some(value) = t.attrs(l).get(color_by) {
+            let next = PALETTE[colors.len() % PALETTE.len()];
+            colors.entry(value.as_str()).or_insert(next);
+        }
+    }
+    let color = |n: usize| {
+        t.attrs(n)
+            .get(color_by)
+            .and_then(|v| colors.get(v.as_str()))
+            .copied()
+            .unwrap_or("black")
+    };
+    let label_width = leaves
+        .iter()
+        .filter_map(|&l| t.name(l))
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0) as f32
+        * CHAR_WIDTH;
+
+    let mut svg = String::new();
+    let edge_style = |c: usize| {
+        if is_loss(t, c) {
+            r#"stroke="grey" stroke-dasharray="4,2""#
+        } else {
+            r#"stroke="black""#
+        }
+    };
+    match layout {
+        Layout::Rectangular => {
+            let height = leaves.len() as f32 * ROW;
+            let pos = |n: usize| {
+                (
+                    MARGIN + depth[&n] / max_depth * WIDTH,
+                    MARGIN + slot[&n] * height,
+                )
+            };
+            for n in t.nodes() {
+                let (x, y) = pos(n);
+                for &c in t[n].children() {
+                    let (cx, cy) = pos(c);
+                    svg.push_str(&format!(
+                        "<path d=\"M{:.1},{:.1}V{:.1}H{:.1}\" fill=\"none\" {}/>\n",
+                        x,
+                        y,
+                        cy,
+                        cx,
+                        edge_style(c)
+                    ));
+                }
+                if t[n].is_leaf() {
+                    if let Some(name) = t.name(n) {
+                        svg.push_str(&format!(
+                            "<text x=\"{:.1}\" y=\"{:.1}\" dominant-baseline=\"middle\" fill=\"{}\">{}</text>\n",
+                            x + 4.,
+                            y,
+                            color(n),
+                            escape(name)
+                        ));
+                    }
+                }
+                if t.is_duplication(n) {
+                    svg.push_str(&format!(
+                        "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"6\" height=\"6\" fill=\"red\"/>\n",
+                        x - 3.,
+                        y - 3.
+                    ));
+                }
+            }
+            Drawing {
+                svg,
+                width: 2. * MARGIN + WIDTH + label_width,
+                height: 2. * MARGIN + height,
+            }
+        }
+        Layout::Circular => {
+            let radius = (leaves.len() as f32 * ROW / (2. * PI)).max(100.);
+            let center = MARGIN + radius + label_width;
+            let polar = |r: f32, a: f32| (center + r * a.cos(), center + r * a.sin());
+            let radius_of = |n: usize| depth[&n] / max_depth * radius;
+            let angle_of = |n: usize| slot[&n] * 2. * PI;
+            for n in t.nodes() {
+                let (r, a) = (radius_of(n), angle_of(n));
+                let children = t[n].children();
+                if let (Some(&first), Some(&last), true) =
+                    (children.first(), children.last(), r > 0.)
+                {
+                    let (a1, a2) = (angle_of(first), angle_of(last));
+                    let (x1, y1) = polar(r, a1);
+                    let (x2, y2) = polar(r, a2);
+                    svg.push_str(&format!(
+                        "<path d=\"M{:.1},{:.1}A{:.1},{:.1} 0 {} 1 {:.1},{:.1}\" fill=\"none\" stroke=\"black\"/>\n",
+                        x1,
+                        y1,
+                        r,
+                        r,
+                        if a2 - a1 > PI { 1 } else { 0 },
+                        x2,
+                        y2
+                    ));
+                }
+                for &c in children {
+                    let a = angle_of(c);
+                    let (x1, y1) = polar(r, a);
+                    let (x2, y2) = polar(radius_of(c), a);
+                    svg.push_str(&format!(
+                        "<path d=\"M{:.1},{:.1}L{:.1},{:.1}\" fill=\"none\" {}/>\n",
+                        x1,
+                        y1,
+                        x2,
+                        y2,
+                        edge_style(c)
+                    ));
+                }
+                if t[n].is_leaf() {
+                    if let Some(name) = t.name(n) {
+                        let (x, y) = polar(r + 4., a);
+                        let degrees = a.to_degrees();
+                        // Keep the labels of the left half readable
+                        let (rotation, anchor) = if degrees > 90. && degrees < 270. {
+                            (degrees + 180., "end")
+                        } else {
+                            (degrees, "start")
+                        };
+                        svg.push_str(&format!(
+                            "<text x=\"{:.1}\" y=\"{:.1}\" dominant-baseline=\"middle\" text-anchor=\"{}\" transform=\"rotate({:.1},{:.1},{:.1})\" fill=\"{}\">{}</text>\n",
+                            x,
+                            y,
+                            anchor,
+                            rotation,
+                            x,
+                            y,
+                            color(n),
+                            escape(name)
+                        ));
+                    }
+                }
+                if t.is_duplication(n) {
+                    let (x, y) = polar(r, a);
+                    svg.push_str(&format!(
+                        "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"6\" height=\"6\" fill=\"red\"/>\n",
+                        x - 3.,
+                        y - 3.
+                    ));
+                }
+            }
+            Drawing {
+                svg,
+                width: 2. * center,
+                height: 2. * center,
+            }
+        }
+    }
+}
+
+/// Stack `drawings` vertically in a standalone SVG document
+pub fn svg_document(drawings: &[Drawing]) -> String {
+    let width = drawings.iter().map(|d| d.width).fold(0., f32::max);
+    let height = drawings.iter().map(|d| d.height).sum::<f32>();
+    let mut r = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" viewBox=\"0 0 {:.0} {:.0}\" font-family=\"Helvetica, sans-serif\" font-size=\"12\">\n",
+        width, height, width, height
+    );
+    let mut y = 0.;
+    for d in drawings {
+        r.push_str(&format!("<g transform=\"translate(0,{:.1})\">\n", y));
+        r.push_str(&d.svg);
+        r.push_str("</g>\n");
+        y += d.height;
+    }
+    r.push_str("</svg>\n");
+    r
+}
+
+/// When to colorize the text output
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ColorMode {
+    /// only when writing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// How to draw a tree as text
+pub struct TextOptions {
+    /// the node labels, where `{name}`, `{length}` and `{<attribute>}` are
+    /// replaced by their value for each node
+    pub template: String,
+    /// whether to label the inner nodes
+    pub inners: bool,
+    /// whether to append the branch lengths to the labels
+    pub lengths: bool,
+    /// whether to use plain ASCII rather than Unicode box drawing characters
+    pub ascii: bool,
+    /// whether to color duplications and species with ANSI escape codes
+    pub colors: bool,
+    /// the maximal width of a line, in characters
+    pub width: Option<usize>,
+    /// the name of the node to display the subtree of, instead of the whole tree
+    pub root: Option<String>,
+    /// the number of levels to display below the root, deeper clades being
+    /// summarized in a single line
+    pub depth: Option<usize>,
+    /// whether to summarize the clades of a single species in a single line
+    pub fold: bool,
+}
+
+/// The ANSI colors given to species, red being reserved for duplications
+const ANSI_PALETTE: [u8; 10] = [32, 33, 34, 35, 36, 92, 93, 94, 95, 96];
+
+/// Fill `template` with the attributes of the node `n` of `t`
+fn fill_template(t: &NewickTree, n: usize, template: &str) -> String {
+    let mut r = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        r.push_str(&rest[..start]);
+        match rest[start..].find('}') {
+            Some(end) => {
+                let key = &rest[start + 1..start + end];
+                match key {
+                    "name" => r.push_str(t.name(n).map(String::as_str).unwrap_or_default()),
+                    "length" => {
+                        if let Some(l) = t[n].branch() {
+                            r.push_str(&l.to_string());
+                        }
+                    }
+                    _ => r.push_str(t.attrs(n).get(key).map(String::as_str).unwrap_or_default()),
+                }
+                rest = &rest[start + end + 1..];
+            }
+            None => {
+                r.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    r.push_str(rest);
+    r
+}
+
+/// The ANSI color of the species `s`, stable whatever the tree
+fn species_color(s: &str) -> String {
+    let hash = s
+        .bytes()
+        .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+    ANSI_PALETTE[hash % ANSI_PALETTE.len()].to_string()
+}
+
+/// The ANSI escape code to use for the label of the node `n` of `t`, if any
+fn ansi_color(t: &NewickTree, n: usize) -> Option<String> {
+    if is_loss(t, n) {
+        Some(String::from("2"))
+    } else if t.is_duplication(n) {
+        Some(String::from("1;31"))
+    } else if t[n].is_leaf() {
+        t.attrs(n).get("S").map(|s| species_color(s))
+    } else {
+        None
+    }
+}
+
+/// For each node of `t`, its number of leaves and the species they all
+/// share, if any
+fn clade_species(t: &NewickTree) -> HashMap<usize, (usize, Option<&str>)> {
+    let mut r: HashMap<usize, (usize, Option<&str>)> = HashMap::with_capacity(t.len());
+    for n in postorder(t) {
+        let clade = if t[n].is_leaf() {
+            (1, t.attrs(n).get("S").map(String::as_str))
+        } else {
+            let mut children = t[n].children().iter().map(|c| r[c]);
+            let first = children.next().unwrap();
+            children.fold(first, |(count, species), (c_count, c_species)| {
+                (count + c_count, species.filter(|_| species == c_species))
+            })
+        };
+        r.insert(n, clade);
+    }
+    r
+}
+
+/// Draw `t` as an indented text tree
+pub fn render_text(t: &NewickTree, options: &TextOptions) -> Result<String> {
+    let (branch, last_branch, pipe, junction, ellipsis) = if options.ascii {
+        ("|-- ", "`-- ", "|   ", "+", "...")
+    } else {
+        ("├── ", "└── ", "│   ", "┐", "…")
+    };
+
+    let mut r = String::new();
+    if t.is_empty() {
+        return Ok(r);
+    }
+    let root = match options.root.as_ref() {
+        Some(name) => t
+            .find_node(|d| d.name.as_ref() == Some(name))
+            .with_context(|| format!("node `{}` not found", name))?,
+        None => t.root(),
+    };
+    let clades = clade_species(t);
+
+    // node, depth, prefix of its line, prefix of its children lines
+    let mut todo = vec![(root, 0, String::new(), String::new())];
+    while let Some((n, depth, prefix, children_prefix)) = todo.pop() {
+        let (leaves, species) = clades[&n];
+        let folded = (options.fold && leaves > 1).then_some(species).flatten();
+        let cut = !t[n].is_leaf() && options.depth.map(|d| depth >= d).unwrap_or(false);
+
+        let mut label = if folded.is_none() && (t[n].is_leaf() || options.inners) {
+            fill_template(t, n, &options.template)
+        } else {
+            String::new()
+        };
+        if options.lengths {
+            if let Some(l) = t[n].branch() {
+                label.push_str(&format!(":{}", l));
+            }
+        }
+        if let Some(species) = folded {
+            label.insert_str(0, &format!("[{} x {}]", species, leaves));
+        } else if cut {
+            if !label.is_empty() {
+                label.push(' ');
+            }
+            label.push_str(&format!("[{} leaves]", leaves));
+        } else if label.is_empty() && !t[n].is_leaf() {
+            label.push_str(junction);
+        }
+
+        if let Some(width) = options.width {
+            let room = width.saturating_sub(prefix.chars().count());
+            if label.chars().count() > room {
+                label = label
+                    .chars()
+                    .take(room.saturating_sub(ellipsis.chars().count()))
+                    .collect::<String>()
+                    + ellipsis;
+            }
+        }
+        r.push_str(&prefix);
+        let color = match folded {
+            Some(species) => Some(species_color(species)),
+            None => ansi_color(t, n),
+        };
+        match color.filter(|_| options.colors) {
+            Some(color) => r.push_str(&format!("\x1b[{}m{}\x1b[0m", color, label)),
+            None => r.push_str(&label),
+        }
+        r.push('\n');
+
+        if folded.is_some() || cut {
+            continue;
+        }
+        let children = t[n].children();
+        for (i, &c) in children.iter().enumerate().rev() {
+            let last = i == children.len() - 1;
+            todo.push((
+                c,
+                depth + 1,
+                format!(
+                    "{}{}",
+                    children_prefix,
+                    if last { last_branch } else { branch }
+                ),
+                format!("{}{}", children_prefix, if last { "    " } else { pipe }),
+            ));
+        }
+    }
+    Ok(r)
+}
@@ -0,0 +1,96 @@
+use newick::{Newick, NewickTree};
+use std::collections::HashMap;
+
+/// A species tree, indexed once and for all to speed up the annotation of
+/// gene trees against it.
+pub struct SpeciesTree {
+    tree: NewickTree,
+    /// species name -> leaf ID
+    leaves: HashMap<String, usize>,
+    /// species or ancestral taxon name -> node ID
+    nodes: HashMap<String, usize>,
+    /// node ID -> (pre-order rank, number of descendants, depth)
+    ranks: HashMap<usize, (usize, usize, usize)>,
+}
+impl SpeciesTree {
+    pub fn new(tree: NewickTree) -> Self {
+        let mut leaves = HashMap::new();
+        let mut nodes = HashMap::new();
+        for n in tree.nodes() {
+            if let Some(name) = tree.name(n) {
+                if tree[n].is_leaf() {
+                    leaves.insert(name.to_owned(), n);
+                }
+                nodes.insert(name.to_owned(), n);
+            }
+        }
+
+        let mut ranks = HashMap::new();
+        let mut todo = vec![(tree.root(), 0, false)];
+        let mut rank = 0;
+        while let Some((n, depth, done)) = todo.pop() {
+            if done {
+                let (start, _, depth) = ranks[&n];
+                ranks.insert(n, (start, rank - start - 1, depth));
+            } else {
+                ranks.insert(n, (rank, 0, depth));
+                rank += 1;
+                todo.push((n, depth, true));
+                todo.extend(tree[n].children().iter().map(|&c| (c, depth + 1, false)));
+            }
+        }
+
+        SpeciesTree {
+            tree,
+            leaves,
+            nodes,
+            ranks,
+        }
+    }
+
+    /// The underlying species tree
+    pub fn tree(&self) -> &NewickTree {
+        &self.tree
+    }
+
+    /// The leaf of the species tree named `name`
+    pub fn leaf(&self, name: &str) -> Option<usize> {
+        self.leaves.get(name).copied()
+    }
+
+    /// The node, leaf or ancestral, of the species tree named `name`
+    pub fn node(&self, name: &str) -> Option<usize> {
+        self.nodes.get(name).copied()
+    }
+
+    pub fn name(&self, n: usize) -> Option<&String> {
+        self.tree.name(n)
+    }
+
+    /// Whether `a` is `b` or one of its ancestors
+    pub fn is_ancestor(&self, a: usize, b: usize) -> bool {
+        let (a_rank, a_size, _) = self.ranks[&a];
+        let (b_rank, _, _) = self.ranks[&b];
+        a_rank <= b_rank && b_rank <= a_rank + a_size
+    }
+
+    /// The most recent common ancestor of `a` and `b`
+    pub fn mrca(&self, mut a: usize, mut b: usize) -> usize {
+        while self.ranks[&a].2 > self.ranks[&b].2 {
+            a = self.tree.parent(a).unwrap();
+        }
+        while self.ranks[&b].2 > self.ranks[&a].2 {
+            b = self.tree.parent(b).unwrap();
+        }
+        while a != b {
+            a = self.tree.parent(a).unwrap();
+            b = self.tree.parent(b).unwrap();
+        }
+        a
+    }
+
+    /// The most recent common ancestor of `xs`, or `None` if it is empty
+    pub fn mrca_of(&self, xs: impl IntoIterator<Item = usize>) -> Option<usize> {
+        xs.into_iter().reduce(|a, b| self.mrca(a, b))
+    }
+}
@@ -1,7 +1,7 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 
-use newick::{Newick, NewickTree};
+use newick::{Data, Newick, NewickTree};
 
 pub fn jaccard<T>(a: &HashSet<T>, b: &HashSet<T>) -> f32
 where
@@ -10,14 +10,32 @@ where
     a.intersection(b).count() as f32 / a.union(b).count() as f32
 }
 
+/// The duplication consistency score of a set of sibling species sets, i.e.
+/// the ratio of the species found in at least two of them over all their
+/// species. For two sets, this is their Jaccard index.
+pub fn duplication_consistency<T>(xs: &[HashSet<T>]) -> f32
+where
+    T: Eq + Hash,
+{
+    let mut counts = HashMap::<&T, usize>::new();
+    for x in xs.iter().flat_map(|x| x.iter()) {
+        *counts.entry(x).or_default() += 1;
+    }
+    counts.values().filter(|&&c| c >= 2).count() as f32 / counts.len() as f32
+}
+
+/// Count the effective losses, i.e. the minimal number of lost clades, that
+/// each of the sibling species sets `xs` should have undergone to only cover
+/// its species out of their union.
+///
+/// Returns the count of all the effective losses, and of those spanning more
+/// than one species.
 pub fn effective_losses(
-    a: &HashSet<usize>,
-    b: &HashSet<usize>,
+    xs: &[HashSet<usize>],
     species: &NewickTree,
     actual_species: &HashSet<usize>,
 ) -> (usize, usize) {
-    let missing_left = a.difference(b).copied().collect::<HashSet<_>>();
-    let missing_right = b.difference(a).copied().collect::<HashSet<_>>();
+    let all = xs.iter().flatten().copied().collect::<HashSet<_>>();
 
     fn els_oneside(
         missing: &HashSet<usize>,
@@ -41,6 +59,7 @@ pub fn effective_losses(
 
         let mut r_large = 0;
         let mut r_all = 0;
+        let mut remaining = missing.clone();
         let mut missing = missing.iter().copied().collect::<Vec<_>>();
 
         while !missing.is_empty() {
@@ -60,7 +79,7 @@ pub fn effective_losses(
                     println!("Candidates: {:?}", id2names(&candidates, species));
                 }
 
-                if !candidates.is_empty() && candidates.iter().all(|x| missing.contains(x)) {
+                if !candidates.is_empty() && candidates.iter().all(|x| remaining.contains(x)) {
                     current = candidates.clone();
                 } else {
                     break 'goup;
@@ -85,14 +104,213 @@ pub fn effective_losses(
                 r_large += 1;
             }
 
-            missing.retain(|x| !current.contains(x));
+            for x in current.iter() {
+                remaining.remove(x);
+            }
+            missing.retain(|x| remaining.contains(x));
         }
         (r_all, r_large)
     }
 
-    let (lr_all, lr_large) = els_oneside(&missing_left, species, actual_species);
-    let (rr_all, rr_large) = els_oneside(&missing_right, species, actual_species);
-    (lr_all + rr_all, lr_large + rr_large)
+    xs.iter()
+        .map(|x| all.difference(x).copied().collect::<HashSet<_>>())
+        .map(|missing| els_oneside(&missing, species, actual_species))
+        .fold((0, 0), |(all, large), (x_all, x_large)| {
+            (all + x_all, large + x_large)
+        })
+}
+
+/// Walk up the species tree from `from` to `to` (excluded), and list, for each
+/// traversed speciation, the sister clades of the followed lineage that
+/// contain at least one of `actual_species` and must thus have been lost.
+///
+/// Returns a list of (speciation, lost clades) pairs, from the bottom up.
+pub fn losses_on_path(
+    from: usize,
+    to: usize,
+    species: &NewickTree,
+    actual_species: &HashSet<usize>,
+) -> Vec<(usize, Vec<usize>)> {
+    let mut r = Vec::new();
+    let mut current = from;
+    while current != to {
+        if let Some(parent) = species.parent(current) {
+            let lost = species[parent]
+                .children()
+                .iter()
+                .copied()
+                .filter(|&c| c != current)
+                .filter(|&c| {
+                    species
+                        .leaves_of(c)
+                        .iter()
+                        .any(|l| actual_species.contains(l))
+                })
+                .collect::<Vec<_>>();
+            if !lost.is_empty() {
+                r.push((parent, lost));
+            }
+            current = parent;
+        } else {
+            break;
+        }
+    }
+    r
+}
+
+/// List the nodes of `t` in post-order, i.e. children before their parent
+pub fn postorder(t: &NewickTree) -> Vec<usize> {
+    let mut r = Vec::with_capacity(t.len());
+    let mut todo = vec![t.root()];
+    while let Some(n) = todo.pop() {
+        r.push(n);
+        todo.extend(t[n].children().iter().copied());
+    }
+    r.reverse();
+    r
+}
+
+/// Copy the subtree of `t` rooted in `n` into a new tree
+pub fn extract_subtree(t: &NewickTree, n: usize) -> NewickTree {
+    fn rec_copy(t: &NewickTree, n: usize, r: &mut NewickTree, parent: Option<usize>) {
+        let new = r.add_node(
+            parent,
+            Data {
+                name: t.name(n).cloned(),
+                attrs: t.attrs(n).clone(),
+            },
+        );
+        if let Some(&l) = t[n].branch() {
+            r[new].set_branch(l);
+        }
+        for &c in t[n].children() {
+            rec_copy(t, c, r, Some(new));
+        }
+    }
+
+    let mut r = NewickTree::new();
+    rec_copy(t, n, &mut r, None);
+    r
+}
+
+fn set_branch(t: &mut NewickTree, n: usize, l: Option<f32>) {
+    if let Some(l) = l {
+        t[n].set_branch(l);
+    } else {
+        t[n].unset_branch();
+    }
+}
+
+/// The NHX attributes describing the branch above a node rather than the
+/// node itself: support, width and color
+const BRANCH_ATTRS: [&str; 3] = ["B", "W", "C"];
+
+/// Remove from the node `n` of `t` the attributes of the branch above it
+fn take_branch_attrs(t: &mut NewickTree, n: usize) -> Vec<(String, String)> {
+    let attrs = t.attrs_mut(n);
+    BRANCH_ATTRS
+        .iter()
+        .filter_map(|&k| attrs.remove_entry(k))
+        .collect()
+}
+
+/// Reroot `t` on the branch between `n` and its parent, splitting its length
+/// in halves. If the former root is left with a single child, it is removed
+/// and its branches are merged; if it was binary and `n` one of its children,
+/// the root is only moved to the middle of the branch joining them.
+pub fn reroot_above(t: &mut NewickTree, n: usize) {
+    let root = t.root();
+    if n != root && t.parent(n) == Some(root) && t[root].children().len() == 2 {
+        // The two branches of the root are the same one, that is halved
+        let sibling = *t[root].children().iter().find(|&&c| c != n).unwrap();
+        let half = match (t[n].branch(), t[sibling].branch()) {
+            (None, None) => None,
+            (a, b) => Some((a.copied().unwrap_or(0.) + b.copied().unwrap_or(0.)) / 2.),
+        };
+        set_branch(t, n, half);
+        set_branch(t, sibling, half);
+        return;
+    }
+    let half = t[n].branch().map(|l| l / 2.);
+    reroot_within(t, n, half);
+}
+
+/// Reroot `t` on the branch between `n` and its parent, at the distance
+/// `below` from `n`. The branch attributes are carried along their branch
+/// when the path to the former root is reversed.
+pub fn reroot_within(t: &mut NewickTree, n: usize, below: Option<f32>) {
+    let old_root = t.root();
+    let parent = if let Some(parent) = t.parent(n).filter(|_| n != old_root) {
+        parent
+    } else {
+        return;
+    };
+
+    let new_root = t.add_node(
+        None,
+        Data {
+            name: None,
+            attrs: HashMap::new(),
+        },
+    );
+    let above = match (t[n].branch(), below) {
+        (Some(l), Some(below)) => Some((l - below).max(0.)),
+        _ => None,
+    };
+    t.move_node(n, new_root);
+    set_branch(t, n, below);
+
+    // Reverse the path from the former parent of `n` to the former root, each
+    // node taking the branch of its former child on the path
+    let mut carried = (
+        above,
+        t.attrs(n)
+            .iter()
+            .filter(|(k, _)| BRANCH_ATTRS.contains(&k.as_str()))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect::<Vec<_>>(),
+    );
+    let mut previous = new_root;
+    let mut current = Some(parent);
+    while let Some(x) = current {
+        current = if x == old_root { None } else { t.parent(x) };
+        let branch = (t[x].branch().copied(), take_branch_attrs(t, x));
+        t.move_node(x, previous);
+        set_branch(t, x, carried.0);
+        t.attrs_mut(x).extend(carried.1);
+        carried = branch;
+        previous = x;
+    }
+
+    if t[old_root].children().len() == 1 {
+        let child = t[old_root].children()[0];
+        let parent = t.parent(old_root).unwrap();
+        let merged = match (t[child].branch(), t[old_root].branch()) {
+            (None, None) => None,
+            (a, b) => Some(a.copied().unwrap_or(0.) + b.copied().unwrap_or(0.)),
+        };
+        for (k, v) in take_branch_attrs(t, old_root) {
+            t.attrs_mut(child).entry(k).or_insert(v);
+        }
+        t.move_node(child, parent);
+        set_branch(t, child, merged);
+        t.delete_node(old_root);
+    } else if t[old_root].is_leaf() {
+        // The former root had a single child, that is now on the other side
+        t.delete_node(old_root);
+    }
+    t.set_root(new_root);
+    t[new_root].unset_branch();
+
+    // Rerooting above the single child of the former root leaves it alone
+    // below the new one
+    if t[new_root].children().len() == 1 {
+        let child = t[new_root].children()[0];
+        t.unplug(child);
+        t.delete_node(new_root);
+        t.set_root(child);
+        t[child].unset_branch();
+    }
 }
 
 pub fn capitalize(s: &str) -> String {
@@ -102,3 +320,40 @@ pub fn capitalize(s: &str) -> String {
         Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reroot_on(newick: &str, name: &str) -> String {
+        let mut t = newick::one_from_string(newick).unwrap();
+        let n = t.find_node(|d| d.name.as_deref() == Some(name)).unwrap();
+        reroot_above(&mut t, n);
+        Newick::to_newick(&t, false)
+    }
+
+    #[test]
+    fn reroot_above_splits_the_branch() {
+        assert_eq!(
+            reroot_on("((A:1,B:2)X:3,(C:4,D:10)Y:1)R;", "C"),
+            "(C:2,(D:10,(A:1,B:2)X:4)Y:2);"
+        );
+    }
+
+    #[test]
+    fn reroot_above_removes_a_single_child_root() {
+        assert_eq!(
+            reroot_on("((A:1,B:2,C:3)X:1)R;", "A"),
+            "(A:0.5,(B:2,C:3)X:0.5);"
+        );
+        assert_eq!(reroot_on("((A:1,B:2,C:3)X:1)R;", "X"), "(A:1,B:2,C:3)X;");
+    }
+
+    #[test]
+    fn reroot_above_keeps_branch_attributes_on_their_branch() {
+        assert_eq!(
+            reroot_on("((A:1,B:2)X:3[&&NHX:B=90],C:4)R;", "A"),
+            "(A:0.5,(B:2,C:7[&&NHX:B=90])X:0.5);"
+        );
+    }
+}